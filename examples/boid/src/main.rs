@@ -120,45 +120,44 @@ struct MousePos(iron_oxide::Vector2);
 /// update loop
 // 2200 particles 100fps
 fn main() {
-    let mut builder = iron_oxide::build();
+    let mut engine = iron_oxide::EngineBuilder::new()
+        .with_system(UpdateVelocity, "update_velocity", &["collide_entities"])
+        .build();
 
-    builder
-        .3
-        .add(UpdateVelocity, "update_velocity", &["collide_entities"]);
-
-    let mut data = iron_oxide::init(builder);
-    let timer = data.0.get_time();
-    data.2.insert(EntCount(0));
-    data.2.insert(MousePos(data.0.get_mouse_position()));
-    data.2.insert(timer);
+    let timer = engine.rl().get_time();
+    let mouse_pos = engine.rl().get_mouse_position();
+    engine.world_mut().insert(EntCount(0));
+    engine.world_mut().insert(MousePos(mouse_pos));
+    engine.world_mut().insert(timer);
 
     let mut rng = iron_oxide::rand::thread_rng();
 
     for _ in 0..1 {
-        gen_enity(&mut data.2, &mut rng);
+        gen_enity(engine.world_mut(), &mut rng);
     }
 
-    while !data.0.window_should_close() {
+    while !engine.should_close() {
         {
-            data.2.write_resource::<MousePos>().0 = data.0.get_mouse_position();
+            engine.world().write_resource::<MousePos>().0 = engine.rl().get_mouse_position();
         }
 
-        if data.0.is_key_pressed(iron_oxide::KeyboardKey::KEY_R) {
-            data.2.write_resource::<EntCount>().0 = 0;
+        if engine.rl().is_key_pressed(iron_oxide::KeyboardKey::KEY_R) {
+            let world = engine.world_mut();
+            world.write_resource::<EntCount>().0 = 0;
 
-            *data.2.write_resource::<Option<iron_oxide::bvh::BVHTree>>() = None;
+            *world.write_resource::<Option<iron_oxide::broad_phase::DynBroadPhase>>() = None;
 
-            data.2.delete_all();
-            data.2.maintain();
+            world.delete_all();
+            world.maintain();
         } else {
-            if data.0.get_fps() > 100 {
-                if data.0.get_time() - timer > 0.01 {
-                    gen_enity(&mut data.2, &mut rng);
+            if engine.rl().get_fps() > 100 {
+                if engine.rl().get_time() - timer > 0.01 {
+                    gen_enity(engine.world_mut(), &mut rng);
                 }
             }
         }
 
-        iron_oxide::update(&mut data, draw);
+        engine.update(draw);
     }
 }
 
@@ -211,17 +210,18 @@ fn draw(world: &mut iron_oxide::World, d: &mut iron_oxide::prelude::RaylibDrawHa
 
         if DEBUG_BVH {
             let mut cost = 0;
-            let bvh_read: iron_oxide::Read<Option<iron_oxide::bvh::BVHTree>> = world.system_data();
-            if let Some(bvh_root) = &*bvh_read {
+            let broad_phase_read: iron_oxide::Read<Option<iron_oxide::broad_phase::DynBroadPhase>> =
+                world.system_data();
+            if let Some(bvh_root) = broad_phase_read.as_ref().and_then(|b| b.as_bvh()) {
                 let p = world.read_resource::<MousePos>().0;
                 for node in bvh_root
-                    .debug_query_point(&p, &[true; iron_oxide::collider::LAYERS])
+                    .debug_query_point(&p, &iron_oxide::collider::LayerMask::ALL)
                     .1
                 {
                     let rect;
                     match node.0 {
                         iron_oxide::bvh::Node::Branch(bb, _) => rect = bb,
-                        iron_oxide::bvh::Node::Fruit(bb, _, _) => rect = bb,
+                        iron_oxide::bvh::Node::Fruit(bb, _) => rect = bb,
                     }
                     cost += 1;
                     let mut color =
@@ -283,10 +283,8 @@ fn gen_enity(world: &mut iron_oxide::World, rng: &mut impl iron_oxide::rand::Rng
             world.write_resource::<EntCount>().0 += 1;
         }
 
-        let mut layers = [false; iron_oxide::collider::LAYERS];
-        layers[0] = true;
-        let mut mask = [false; iron_oxide::collider::LAYERS];
-        mask[0] = true;
+        let layers = iron_oxide::collider::LayerMask::layer(0);
+        let mask = iron_oxide::collider::LayerMask::layer(0);
 
         let collider = iron_oxide::collider::Collider {
             shape: iron_oxide::collider::Shape::CircleCollider { radius: radius },
@@ -0,0 +1,357 @@
+extern crate iron_oxide;
+
+use std::time::Instant;
+
+use iron_oxide::broad_phase::{BroadPhase, DynBroadPhase, SpatialHash, SweepAndPrune};
+use iron_oxide::bvh::{BVHTree, BuildStrategy};
+use iron_oxide::collider::{Collider, LayerMask, Shape, AABB};
+use iron_oxide::rand::Rng;
+use iron_oxide::Vector2;
+
+const ENTITIES: usize = 12000;
+const BUILDS: usize = 20;
+const POINT_QUERIES: usize = 10000;
+const WORLD_SIZE: [f32; 2] = [1400f32, 1000f32];
+const THREAD_COUNTS: [usize; 4] = [1, 2, 4, 8];
+const BULLET_SIZE: f32 = 8f32;
+const BULLET_SPEED: f32 = 4f32;
+
+/// Compares the median split and surface area heuristic bvh builds,
+/// then how building and shrinking the tree scales with the number of threads,
+/// then how fast a tree grown one entity at a time is queried before and after its nodes are put in depth first order,
+/// and how a tree built with a median split compares to the same tree where every node is a separate allocation,
+/// then how each broad phase keeps up with entities of the same size that all move every frame,
+/// then how the pairs of a tree are found with a query for every entity or one traversal of the tree against itself.
+/// Run with `cargo run --release -p bvh_bench`, no window is opened.
+fn main() {
+    let mut rng = iron_oxide::rand::thread_rng();
+
+    // mostly small entities with a few large ones, where the two builds differ the most
+    let entities: Vec<(Collider, Vector2)> = (0..ENTITIES)
+        .map(|_| {
+            let size = if rng.gen_bool(0.05) {
+                Vector2::new(rng.gen_range(50f32..300f32), rng.gen_range(50f32..300f32))
+            } else {
+                Vector2::new(rng.gen_range(2f32..10f32), rng.gen_range(2f32..10f32))
+            };
+            let collider = Collider {
+                shape: Shape::RectangeCollider { size },
+                physics_collider: true,
+                collision_layers: LayerMask::layer(0),
+                collision_mask: LayerMask::layer(0),
+            };
+            let pos = Vector2::new(
+                rng.gen_range(0f32..WORLD_SIZE[0] - size.x),
+                rng.gen_range(0f32..WORLD_SIZE[1] - size.y),
+            );
+            (collider, pos)
+        })
+        .collect();
+    let points: Vec<Vector2> = (0..POINT_QUERIES)
+        .map(|_| {
+            Vector2::new(
+                rng.gen_range(0f32..WORLD_SIZE[0]),
+                rng.gen_range(0f32..WORLD_SIZE[1]),
+            )
+        })
+        .collect();
+
+    println!(
+        "{} entities, build time averaged over {} builds",
+        ENTITIES, BUILDS
+    );
+    println!(
+        "{:<8} {:>12} {:>16} {:>16}",
+        "strategy", "build (ms)", "cost per rect", "cost per point"
+    );
+    for &strategy in &[BuildStrategy::Median, BuildStrategy::Sah] {
+        let build_time = time_builds(&entities, strategy);
+        let bvh = build(&entities, strategy);
+
+        // the cost is the number of nodes visited, the same as the counter drawn in the examples
+        let mut rect_cost = 0;
+        for (collider, pos) in &entities {
+            rect_cost += bvh
+                .debug_query_rect(&collider.get_bounding_box(pos), &LayerMask::ALL)
+                .1
+                .len();
+        }
+        let mut point_cost = 0;
+        for p in &points {
+            point_cost += bvh.debug_query_point(p, &LayerMask::ALL).1.len();
+        }
+
+        println!(
+            "{:<8} {:>12.3} {:>16.2} {:>16.2}",
+            format!("{:?}", strategy),
+            build_time,
+            rect_cost as f64 / ENTITIES as f64,
+            point_cost as f64 / POINT_QUERIES as f64
+        );
+    }
+
+    println!();
+    println!(
+        "{:<8} {:>12} {:>12} {:>12}",
+        "threads", "median (ms)", "sah (ms)", "shrink (ms)"
+    );
+    for &threads in &THREAD_COUNTS {
+        let pool = iron_oxide::rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let (median_time, sah_time, shrink_time) = pool.install(|| {
+            let median_time = time_builds(&entities, BuildStrategy::Median);
+            let sah_time = time_builds(&entities, BuildStrategy::Sah);
+            let mut bvh = build(&entities, BuildStrategy::Median);
+            let start = Instant::now();
+            for _ in 0..BUILDS {
+                bvh.shrink();
+            }
+            let shrink_time = start.elapsed().as_secs_f64() * 1000f64 / BUILDS as f64;
+            (median_time, sah_time, shrink_time)
+        });
+        println!(
+            "{:<8} {:>12.3} {:>12.3} {:>12.3}",
+            threads, median_time, sah_time, shrink_time
+        );
+    }
+
+    // inserted nodes are stored in the order they were created, which scatters each query across memory
+    let mut bvh = build(&entities[..1], BuildStrategy::Median);
+    for (id, (collider, pos)) in entities.iter().enumerate().skip(1) {
+        bvh.insert(&(collider, *pos, collider.get_bounding_box(pos), id as u32));
+    }
+    println!();
+    println!("{:<12} {:>12}", "layout", "queries (ms)");
+    let query_bvh = |bvh: &BVHTree, bb: &AABB| bvh.get_tree().query_rect(bb, |_| true).len();
+    println!(
+        "{:<12} {:>12.3}",
+        "inserted",
+        time_queries(&entities, |bb| query_bvh(&bvh, bb))
+    );
+    bvh.optimize_layout();
+    println!(
+        "{:<12} {:>12.3}",
+        "depth first",
+        time_queries(&entities, |bb| query_bvh(&bvh, bb))
+    );
+    // both built with a median split, so only where the nodes are stored differs
+    let built = build(&entities, BuildStrategy::Median);
+    println!(
+        "{:<12} {:>12.3}",
+        "median",
+        time_queries(&entities, |bb| query_bvh(&built, bb))
+    );
+    let boxed = BoxedNode::build(
+        entities
+            .iter()
+            .enumerate()
+            .map(|(id, (collider, pos))| (collider.get_bounding_box(pos), id as u32))
+            .collect(),
+    );
+    println!(
+        "{:<12} {:>12.3}",
+        "boxed",
+        time_queries(&entities, |bb| {
+            let mut found = Vec::new();
+            boxed.query_rect(bb, &mut found);
+            found.len()
+        })
+    );
+
+    // entities of the same size moving in straight lines, like the bullets of a shooter
+    let bullet = Collider {
+        shape: Shape::RectangeCollider {
+            size: Vector2::new(BULLET_SIZE, BULLET_SIZE),
+        },
+        physics_collider: true,
+        collision_layers: LayerMask::layer(0),
+        collision_mask: LayerMask::layer(0),
+    };
+    let bullets: Vec<(Vector2, Vector2)> = (0..ENTITIES)
+        .map(|_| {
+            let pos = Vector2::new(
+                rng.gen_range(0f32..WORLD_SIZE[0]),
+                rng.gen_range(0f32..WORLD_SIZE[1]),
+            );
+            let velocity = Vector2::new(
+                rng.gen_range(-BULLET_SPEED..BULLET_SPEED),
+                rng.gen_range(-BULLET_SPEED..BULLET_SPEED),
+            );
+            (pos, velocity)
+        })
+        .collect();
+    let data = || -> Vec<_> {
+        bullets
+            .iter()
+            .enumerate()
+            .map(|(id, (pos, _))| (&bullet, *pos, bullet.get_bounding_box(pos), id as u32))
+            .collect()
+    };
+    let broad_phases: Vec<(&str, DynBroadPhase)> = vec![
+        ("bvh", Box::new(BVHTree::new(data()))),
+        (
+            "grid",
+            Box::new(SpatialHash::new(BULLET_SIZE * 2f32, data())),
+        ),
+        ("sweep", Box::new(SweepAndPrune::new(data()))),
+    ];
+    println!();
+    println!(
+        "{:<12} {:>12} {:>12} {:>12}",
+        "broad phase", "update (ms)", "pairs (ms)", "pairs"
+    );
+    for (name, mut broad_phase) in broad_phases {
+        let (update_time, pairs_time, pairs) = time_frames(broad_phase.as_mut(), &bullet, &bullets);
+        println!(
+            "{:<12} {:>12.3} {:>12.3} {:>12}",
+            name, update_time, pairs_time, pairs
+        );
+    }
+
+    // a query from every entity finds each pair from both sides, the traversal of the tree against itself finds it once
+    let bvh = build(&entities, BuildStrategy::Median);
+    println!();
+    println!("{:<12} {:>12} {:>12}", "bvh pairs", "time (ms)", "pairs");
+    let start = Instant::now();
+    let mut found = 0;
+    for _ in 0..BUILDS {
+        found = 0;
+        for (id, (collider, pos)) in entities.iter().enumerate() {
+            found += bvh
+                .query_rect(&collider.get_bounding_box(pos), &LayerMask::ALL)
+                .into_iter()
+                .filter(|other| *other != id as u32)
+                .count();
+        }
+    }
+    println!(
+        "{:<12} {:>12.3} {:>12}",
+        "queries",
+        start.elapsed().as_secs_f64() * 1000f64 / BUILDS as f64,
+        found
+    );
+    let start = Instant::now();
+    for _ in 0..BUILDS {
+        found = bvh.overlapping_pairs(|_, _| true).len();
+    }
+    println!(
+        "{:<12} {:>12.3} {:>12}",
+        "traversal",
+        start.elapsed().as_secs_f64() * 1000f64 / BUILDS as f64,
+        found
+    );
+}
+
+/// Move the entities for a number of frames, and get the average time in milliseconds to update the broad phase and find the pairs,
+/// along with the number of pairs found in the last frame.
+fn time_frames(
+    broad_phase: &mut dyn BroadPhase,
+    collider: &Collider,
+    bullets: &[(Vector2, Vector2)],
+) -> (f64, f64, usize) {
+    let mut update_time = 0f64;
+    let mut pairs_time = 0f64;
+    let mut pairs = 0;
+    for frame in 1..=BUILDS {
+        let start = Instant::now();
+        for (id, (pos, velocity)) in bullets.iter().enumerate() {
+            let pos = *pos + *velocity * frame as f32;
+            broad_phase.update(id as u32, &collider.get_bounding_box(&pos), *velocity);
+        }
+        update_time += start.elapsed().as_secs_f64();
+        let start = Instant::now();
+        pairs = broad_phase.pairs(&|_, _| true).len();
+        pairs_time += start.elapsed().as_secs_f64();
+    }
+    (
+        update_time * 1000f64 / BUILDS as f64,
+        pairs_time * 1000f64 / BUILDS as f64,
+        pairs,
+    )
+}
+
+/// Get the average time in milliseconds to query the bounding box of every entity,
+/// the query returns the number of entities it found.
+fn time_queries(entities: &[(Collider, Vector2)], query: impl Fn(&AABB) -> usize) -> f64 {
+    let start = Instant::now();
+    let mut found = 0;
+    for _ in 0..BUILDS {
+        for (collider, pos) in entities {
+            found += query(&collider.get_bounding_box(pos));
+        }
+    }
+    // use the result so the queries aren't optimized away
+    assert!(found >= entities.len() * BUILDS);
+    start.elapsed().as_secs_f64() * 1000f64 / BUILDS as f64
+}
+
+/// Get the average time in milliseconds to build the tree.
+fn time_builds(entities: &[(Collider, Vector2)], strategy: BuildStrategy) -> f64 {
+    let start = Instant::now();
+    for _ in 0..BUILDS {
+        build(entities, strategy);
+    }
+    start.elapsed().as_secs_f64() * 1000f64 / BUILDS as f64
+}
+
+/// Build a bvh tree from the entities.
+fn build(entities: &[(Collider, Vector2)], strategy: BuildStrategy) -> BVHTree {
+    let data: Vec<(&Collider, Vector2, AABB, u32)> = entities
+        .iter()
+        .enumerate()
+        .map(|(id, (collider, pos))| (collider, *pos, collider.get_bounding_box(pos), id as u32))
+        .collect();
+    BVHTree::with_strategy(data, strategy)
+}
+
+/// The layout the bvh tree used before its nodes were stored in one list, every child is a separate allocation.
+enum BoxedNode {
+    Branch(AABB, Box<BoxedNode>, Box<BoxedNode>),
+    Fruit(AABB, u32),
+}
+
+impl BoxedNode {
+    /// Build the tree by splitting the entities at the median of the longest side of their bounding box.
+    fn build(mut leaves: Vec<(AABB, u32)>) -> BoxedNode {
+        if leaves.len() == 1 {
+            let (bb, id) = leaves.pop().unwrap();
+            return BoxedNode::Fruit(bb, id);
+        }
+        let bb = leaves[1..]
+            .iter()
+            .fold(leaves[0].0.clone(), |bb, (other, _)| bb.get_union(other));
+        let split_x = bb.rx - bb.lx > bb.ry - bb.ly;
+        let center = |leaf: &AABB| {
+            if split_x {
+                leaf.lx + leaf.rx
+            } else {
+                leaf.ly + leaf.ry
+            }
+        };
+        leaves.sort_unstable_by(|a, b| center(&a.0).partial_cmp(&center(&b.0)).unwrap());
+        let right = leaves.split_off(leaves.len() / 2);
+        BoxedNode::Branch(
+            bb,
+            Box::new(BoxedNode::build(leaves)),
+            Box::new(BoxedNode::build(right)),
+        )
+    }
+
+    fn query_rect(&self, r: &AABB, found: &mut Vec<u32>) {
+        match self {
+            BoxedNode::Branch(bb, left, right) => {
+                if bb.is_colliding(r) {
+                    left.query_rect(r, found);
+                    right.query_rect(r, found);
+                }
+            }
+            BoxedNode::Fruit(bb, id) => {
+                if bb.is_colliding(r) {
+                    found.push(*id);
+                }
+            }
+        }
+    }
+}
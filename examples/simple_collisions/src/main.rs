@@ -2,7 +2,7 @@ extern crate iron_oxide;
 
 use std::ffi::CString;
 
-use iron_oxide::bvh::BVHTree;
+use iron_oxide::broad_phase::DynBroadPhase;
 use iron_oxide::raylib::rgui::RaylibDrawGui;
 use iron_oxide::Builder;
 use iron_oxide::Color;
@@ -24,70 +24,75 @@ struct SettingsState {
 /// update loop
 // 12000 particles 100fps
 fn main() {
-    let builder = iron_oxide::build();
+    let mut engine = iron_oxide::EngineBuilder::new().build();
 
-    let mut data = iron_oxide::init(builder);
-    let timer = data.0.get_time();
-    data.2.insert(EntCount(0));
-    data.2.insert(SettingsState {
+    let timer = engine.rl().get_time();
+    let mouse_pos = engine.rl().get_mouse_position();
+    engine.world_mut().insert(EntCount(0));
+    engine.world_mut().insert(SettingsState {
         debug_bvh: false,
         debug_aabb: false,
         show_velocity: false,
         radius: 15.0,
     });
-    data.2.insert(MousePos(data.0.get_mouse_position()));
-    data.2.insert(timer);
+    engine.world_mut().insert(MousePos(mouse_pos));
+    engine.world_mut().insert(timer);
 
     let mut rng = iron_oxide::rand::thread_rng();
 
     let mut speed = 1.0;
 
-    while !data.0.window_should_close() {
-        let l_m_down = data
-            .0
+    while !engine.should_close() {
+        let l_m_down = engine
+            .rl()
             .is_mouse_button_down(iron_oxide::MouseButton::MOUSE_LEFT_BUTTON);
 
         {
-            data.2.write_resource::<MousePos>().0 = data.0.get_mouse_position();
+            engine.world().write_resource::<MousePos>().0 = engine.rl().get_mouse_position();
         }
 
         {
-            let speed_delta = data.0.get_mouse_wheel_move() / 10.0;
+            let speed_delta = engine.rl().get_mouse_wheel_move() / 10.0;
             speed += speed_delta;
-            let mut delta = data.2.write_resource::<iron_oxide::utils::Delta>();
-            *delta = iron_oxide::utils::Delta(data.0.get_frame_time() * speed);
+            let mut delta = engine.world().write_resource::<iron_oxide::utils::Delta>();
+            *delta = iron_oxide::utils::Delta(engine.rl().get_frame_time() * speed);
         }
 
         if l_m_down {
-            let pos = data.2.read_resource::<MousePos>().0;
+            let world = engine.world_mut();
+            let pos = world.read_resource::<MousePos>().0;
             {
-                let bvh_option = &mut *data.2.write_resource::<Option<BVHTree>>();
-                if let Some(bvh) = bvh_option {
-                    let ents = bvh.query_point(&pos, &[true; iron_oxide::collider::LAYERS]);
+                let broad_phase_option = &mut *world.write_resource::<Option<DynBroadPhase>>();
+                if let Some(broad_phase) = broad_phase_option {
+                    let ents = broad_phase.query_point(&pos, &iron_oxide::collider::LayerMask::ALL);
                     if ents.len() > 0 {
                         let id = ents[0];
-                        let mut entities: iron_oxide::Entities = data.2.system_data();
+                        let mut entities: iron_oxide::Entities = world.system_data();
 
-                        iron_oxide::utils::delete_ent(id, &mut entities, bvh);
+                        iron_oxide::utils::delete_ent(id, &mut entities, broad_phase.as_mut());
                     }
                 }
             }
         }
-        if data.0.is_key_pressed(iron_oxide::KeyboardKey::KEY_R) {
+        if engine.rl().is_key_pressed(iron_oxide::KeyboardKey::KEY_R) {
             speed = 1.0;
 
-            data.2.write_resource::<EntCount>().0 = 0;
-            *data.2.write_resource::<Option<iron_oxide::bvh::BVHTree>>() = None;
+            let world = engine.world_mut();
+            world.write_resource::<EntCount>().0 = 0;
+            *world.write_resource::<Option<iron_oxide::broad_phase::DynBroadPhase>>() = None;
 
-            data.2.delete_all();
-            data.2.maintain();
+            world.delete_all();
+            world.maintain();
         }
-        if data.0.is_key_down(iron_oxide::KeyboardKey::KEY_LEFT_SHIFT) {
-            if data.0.get_time() - timer > 0.01 {
-                gen_enity(&mut data.2, &mut rng);
+        if engine
+            .rl()
+            .is_key_down(iron_oxide::KeyboardKey::KEY_LEFT_SHIFT)
+        {
+            if engine.rl().get_time() - timer > 0.01 {
+                gen_enity(engine.world_mut(), &mut rng);
             }
         }
-        iron_oxide::update(&mut data, draw);
+        engine.update(draw);
     }
 }
 
@@ -182,18 +187,19 @@ fn draw(world: &mut iron_oxide::World, d: &mut iron_oxide::prelude::RaylibDrawHa
 
         if world.read_resource::<SettingsState>().debug_bvh {
             let mut cost = 0;
-            let bvh_read: iron_oxide::Read<Option<iron_oxide::bvh::BVHTree>> = world.system_data();
-            if let Some(bvh_root) = &*bvh_read {
+            let broad_phase_read: iron_oxide::Read<Option<iron_oxide::broad_phase::DynBroadPhase>> =
+                world.system_data();
+            if let Some(bvh_root) = broad_phase_read.as_ref().and_then(|b| b.as_bvh()) {
                 let p = world.read_resource::<MousePos>().0;
                 for node in bvh_root
-                    .debug_query_point(&p, &[true; iron_oxide::collider::LAYERS])
+                    .debug_query_point(&p, &iron_oxide::collider::LayerMask::ALL)
                     .1
                 {
                     let rect;
                     match node.0 {
                         iron_oxide::bvh::Node::Branch(bb, _) => rect = bb,
                         // iron_oxide::bvh::Node::Branch(bb, _, _) => rect = bb,
-                        iron_oxide::bvh::Node::Fruit(bb, _, _) => rect = bb,
+                        iron_oxide::bvh::Node::Fruit(bb, _) => rect = bb,
                     }
                     cost += 1;
                     let mut color =
@@ -252,10 +258,8 @@ fn gen_enity(world: &mut iron_oxide::World, rng: &mut impl iron_oxide::rand::Rng
         world.write_resource::<EntCount>().0 += 1;
     }
 
-    let mut layers = [false; iron_oxide::collider::LAYERS];
-    layers[0] = true;
-    let mut mask = [false; iron_oxide::collider::LAYERS];
-    mask[0] = true;
+    let layers = iron_oxide::collider::LayerMask::layer(0);
+    let mask = iron_oxide::collider::LayerMask::layer(0);
 
     let collider = iron_oxide::collider::Collider {
         shape: iron_oxide::collider::Shape::CircleCollider { radius: radius },
@@ -0,0 +1,807 @@
+use crate::bvh::BVHTree;
+use crate::collider;
+use crate::collider::{LayerMask, AABB};
+use raylib::core::math::Vector2;
+use std::collections::{HashMap, HashSet};
+
+/// An entity as it is added to a broad phase, its collider, position, bounding box and id.
+pub type EntityData<'a> = (&'a collider::Collider, Vector2, AABB, u32);
+
+/// The broad phase stored in the world. It is None until the engine builds it from the entities.
+pub type DynBroadPhase = Box<dyn BroadPhase>;
+
+/// Finds the entities whose bounding boxes could be touching, before the exact shapes are checked.
+/// The engine keeps one in the world as an `Option<DynBroadPhase>`, which backend is used is picked with `PhysicsConfig::broad_phase`.
+pub trait BroadPhase: Send + Sync {
+    /// Add an entity, replacing it if it is already in the broad phase.
+    fn insert(&mut self, new: &EntityData);
+
+    /// Move an entity to a new bounding box, returns false if the entity is not in the broad phase.
+    /// The displacement is how far the entity moved this frame.
+    fn update(&mut self, id: u32, bb: &AABB, displacement: Vector2) -> bool;
+
+    /// Remove an entity, returns false if it was not in the broad phase.
+    fn remove(&mut self, id: u32) -> bool;
+
+    /// Get the layers an entity was inserted with.
+    fn get_layers(&self, id: u32) -> Option<LayerMask>;
+
+    /// Get the bounding box an entity was last inserted or updated with.
+    fn get_bounding_box(&self, id: u32) -> Option<AABB>;
+
+    /// Get the ids of all the entities.
+    fn get_ids(&self) -> Vec<u32>;
+
+    /// Get the number of entities.
+    fn len(&self) -> usize;
+
+    /// Check if there are no entities.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the ids of the entities on any of the layers that collide with a bounding box.
+    fn query_rect(&self, r: &AABB, layers: &LayerMask) -> Vec<u32>;
+
+    /// Get the ids of the entities on any of the layers that collide with a point.
+    fn query_point(&self, p: &Vector2, layers: &LayerMask) -> Vec<u32>;
+
+    /// Get every pair of entities whose bounding boxes collide, each pair once with the smaller id first.
+    /// The filter gets the layers of both entities and decides if the pair is kept.
+    fn pairs(&self, filter: &dyn Fn(&LayerMask, &LayerMask) -> bool) -> Vec<(u32, u32)>;
+
+    /// Get the bvh tree if this broad phase is one, for debug drawing and raycasts.
+    fn as_bvh(&self) -> Option<&BVHTree> {
+        None
+    }
+}
+
+/// The kinds of broad phase the engine can build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BroadPhaseKind {
+    /// A `BVHTree`, which handles entities of any size. It is built with `PhysicsConfig::bvh_build_strategy`.
+    Bvh,
+    /// A `SpatialHash` with cells of `PhysicsConfig::grid_cell_size`, fastest when the entities are about the same size.
+    Grid,
+    /// A `SweepAndPrune`, which does well when few entities overlap on the x axis.
+    SweepAndPrune,
+}
+
+impl Default for BroadPhaseKind {
+    fn default() -> Self {
+        BroadPhaseKind::Bvh
+    }
+}
+
+/// Check if a bounding box contains a point, the edges don't count.
+fn contains_point(bb: &AABB, p: &Vector2) -> bool {
+    bb.lx < p.x && bb.rx > p.x && bb.ly < p.y && bb.ry > p.y
+}
+
+/// Put a pair of ids in order, the smaller id first.
+fn ordered(a: u32, b: u32) -> (u32, u32) {
+    if a < b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+impl BroadPhase for BVHTree {
+    fn insert(&mut self, new: &EntityData) {
+        BVHTree::insert(self, new);
+    }
+
+    fn update(&mut self, id: u32, bb: &AABB, displacement: Vector2) -> bool {
+        BVHTree::update(self, id, bb, displacement)
+    }
+
+    fn remove(&mut self, id: u32) -> bool {
+        self.delete(id)
+    }
+
+    fn get_layers(&self, id: u32) -> Option<LayerMask> {
+        BVHTree::get_layers(self, id)
+    }
+
+    fn get_bounding_box(&self, id: u32) -> Option<AABB> {
+        BVHTree::get_bounding_box(self, id)
+    }
+
+    fn get_ids(&self) -> Vec<u32> {
+        self.get_children_id()
+    }
+
+    fn len(&self) -> usize {
+        BVHTree::len(self)
+    }
+
+    fn query_rect(&self, r: &AABB, layers: &LayerMask) -> Vec<u32> {
+        BVHTree::query_rect(self, r, layers)
+    }
+
+    fn query_point(&self, p: &Vector2, layers: &LayerMask) -> Vec<u32> {
+        BVHTree::query_point(self, p, layers)
+    }
+
+    fn pairs(&self, filter: &dyn Fn(&LayerMask, &LayerMask) -> bool) -> Vec<(u32, u32)> {
+        self.overlapping_pairs(filter)
+    }
+
+    fn as_bvh(&self) -> Option<&BVHTree> {
+        Some(self)
+    }
+}
+
+/// The most cells a bounding box can be put in, larger boxes are checked against everything instead.
+const MAX_CELLS: i64 = 1024;
+
+/// The first and last cell of a bounding box.
+type CellRange = ((i32, i32), (i32, i32));
+
+/// Sorts the bounding boxes into a uniform grid of cells stored in a hash map, so only boxes that share a cell are checked against each other.
+/// Entities much larger than a cell are in many cells, which makes them slow to move.
+/// Boxes that would be in more than `MAX_CELLS` cells, or that aren't finite, are kept out of the grid and checked against everything.
+pub struct SpatialHash {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<u32>>,
+    /// The bounding box and layers of every entity.
+    entries: HashMap<u32, (AABB, LayerMask)>,
+    /// The entities that are too large for the grid.
+    oversized: HashSet<u32>,
+}
+
+impl SpatialHash {
+    /// Create a new grid from the entities, panics if the cell size isn't a positive number.
+    pub fn new(cell_size: f32, data: Vec<EntityData>) -> SpatialHash {
+        assert!(
+            cell_size.is_finite() && cell_size > 0f32,
+            "the cell size must be a positive number"
+        );
+        let mut grid = SpatialHash {
+            cell_size,
+            cells: HashMap::new(),
+            entries: HashMap::with_capacity(data.len()),
+            oversized: HashSet::new(),
+        };
+        for e in &data {
+            BroadPhase::insert(&mut grid, e);
+        }
+        grid
+    }
+
+    /// Get the cell a point is in.
+    fn get_cell(&self, p: &Vector2) -> (i32, i32) {
+        (
+            (p.x / self.cell_size).floor() as i32,
+            (p.y / self.cell_size).floor() as i32,
+        )
+    }
+
+    /// Get the first and last cell a bounding box is in, None if it isn't finite or is in more than `MAX_CELLS` cells.
+    fn get_cell_range(&self, bb: &AABB) -> Option<CellRange> {
+        if ![bb.lx, bb.ly, bb.rx, bb.ry].iter().all(|v| v.is_finite()) {
+            return None;
+        }
+        // far away coordinates are clamped to the last cell, which is still correct as the boxes are checked after
+        let (lx, ly) = self.get_cell(&Vector2::new(bb.lx, bb.ly));
+        let (rx, ry) = self.get_cell(&Vector2::new(bb.rx, bb.ry));
+        let cells = (rx as i64 - lx as i64 + 1).saturating_mul(ry as i64 - ly as i64 + 1);
+        if cells > MAX_CELLS {
+            return None;
+        }
+        Some(((lx, ly), (rx, ry)))
+    }
+
+    /// Add an entity to all of the cells in a range, or to the oversized entities if there is no range.
+    fn add_to_cells(&mut self, id: u32, range: Option<CellRange>) {
+        let ((lx, ly), (rx, ry)) = match range {
+            Some(range) => range,
+            None => {
+                self.oversized.insert(id);
+                return;
+            }
+        };
+        for x in lx..=rx {
+            for y in ly..=ry {
+                self.cells.entry((x, y)).or_default().push(id);
+            }
+        }
+    }
+
+    /// Remove an entity from all of the cells in a range, dropping cells that end up empty.
+    fn remove_from_cells(&mut self, id: u32, range: Option<CellRange>) {
+        let ((lx, ly), (rx, ry)) = match range {
+            Some(range) => range,
+            None => {
+                self.oversized.remove(&id);
+                return;
+            }
+        };
+        for x in lx..=rx {
+            for y in ly..=ry {
+                if let Some(cell) = self.cells.get_mut(&(x, y)) {
+                    if let Some(i) = cell.iter().position(|e| *e == id) {
+                        cell.swap_remove(i);
+                    }
+                    if cell.is_empty() {
+                        self.cells.remove(&(x, y));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Get the ids of the entities on any of the layers in the cells of a bounding box that pass a check.
+    fn query_cells(&self, r: &AABB, layers: &LayerMask, check: impl Fn(&AABB) -> bool) -> Vec<u32> {
+        let passes = |id: &u32| {
+            let (bb, entry_layers) = &self.entries[id];
+            entry_layers.intersects(layers) && check(bb)
+        };
+        let ((lx, ly), (rx, ry)) = match self.get_cell_range(r) {
+            Some(range) => range,
+            // a query too large for the grid checks every entity
+            None => return self.entries.keys().copied().filter(passes).collect(),
+        };
+        let mut result: Vec<u32> = self.oversized.iter().copied().filter(passes).collect();
+        for x in lx..=rx {
+            for y in ly..=ry {
+                if let Some(cell) = self.cells.get(&(x, y)) {
+                    result.extend(cell.iter().copied().filter(passes));
+                }
+            }
+        }
+        // an entity in several cells is found once for each of them
+        result.sort_unstable();
+        result.dedup();
+        result
+    }
+}
+
+impl BroadPhase for SpatialHash {
+    fn insert(&mut self, new: &EntityData) {
+        self.remove(new.3);
+        let range = self.get_cell_range(&new.2);
+        self.add_to_cells(new.3, range);
+        self.entries
+            .insert(new.3, (new.2.clone(), new.0.collision_layers));
+    }
+
+    fn update(&mut self, id: u32, bb: &AABB, _displacement: Vector2) -> bool {
+        let old_range = match self.entries.get(&id) {
+            Some((old_bb, _)) => self.get_cell_range(old_bb),
+            None => return false,
+        };
+        let new_range = self.get_cell_range(bb);
+        if new_range != old_range {
+            self.remove_from_cells(id, old_range);
+            self.add_to_cells(id, new_range);
+        }
+        self.entries.get_mut(&id).unwrap().0 = bb.clone();
+        true
+    }
+
+    fn remove(&mut self, id: u32) -> bool {
+        match self.entries.remove(&id) {
+            Some((bb, _)) => {
+                let range = self.get_cell_range(&bb);
+                self.remove_from_cells(id, range);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn get_layers(&self, id: u32) -> Option<LayerMask> {
+        self.entries.get(&id).map(|e| e.1)
+    }
+
+    fn get_bounding_box(&self, id: u32) -> Option<AABB> {
+        self.entries.get(&id).map(|e| e.0.clone())
+    }
+
+    fn get_ids(&self) -> Vec<u32> {
+        self.entries.keys().copied().collect()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn query_rect(&self, r: &AABB, layers: &LayerMask) -> Vec<u32> {
+        self.query_cells(r, layers, |bb| bb.is_colliding(r))
+    }
+
+    fn query_point(&self, p: &Vector2, layers: &LayerMask) -> Vec<u32> {
+        let r = AABB {
+            lx: p.x,
+            rx: p.x,
+            ly: p.y,
+            ry: p.y,
+        };
+        self.query_cells(&r, layers, |bb| contains_point(bb, p))
+    }
+
+    fn pairs(&self, filter: &dyn Fn(&LayerMask, &LayerMask) -> bool) -> Vec<(u32, u32)> {
+        let mut pairs = Vec::new();
+        for (cell, ids) in &self.cells {
+            for (i, a) in ids.iter().enumerate() {
+                let (bb_a, layers_a) = &self.entries[a];
+                for b in &ids[i + 1..] {
+                    let (bb_b, layers_b) = &self.entries[b];
+                    if !bb_a.is_colliding(bb_b) || !filter(layers_a, layers_b) {
+                        continue;
+                    }
+                    // boxes that share several cells are only paired in the cell with the corner of their intersection
+                    let corner = Vector2::new(bb_a.lx.max(bb_b.lx), bb_a.ly.max(bb_b.ly));
+                    if self.get_cell(&corner) == *cell {
+                        pairs.push(ordered(*a, *b));
+                    }
+                }
+            }
+        }
+        for a in &self.oversized {
+            let (bb_a, layers_a) = &self.entries[a];
+            for (b, (bb_b, layers_b)) in &self.entries {
+                // two oversized entities are paired by the one with the smaller id
+                if a == b || (b < a && self.oversized.contains(b)) {
+                    continue;
+                }
+                if bb_a.is_colliding(bb_b) && filter(layers_a, layers_b) {
+                    pairs.push(ordered(*a, *b));
+                }
+            }
+        }
+        pairs
+    }
+}
+
+/// An entity in the sweep and prune list.
+#[derive(Debug, Clone)]
+struct SapEntry {
+    bb: AABB,
+    layers: LayerMask,
+    id: u32,
+    /// Removed entries stay in the list until it is compacted, so removing doesn't shift every entry after it.
+    removed: bool,
+}
+
+/// Keeps the bounding boxes sorted by their left side, so only boxes that overlap on the x axis are checked against each other.
+/// The list is fixed with an insertion sort as the entities move, which is fast when they move a little each frame.
+pub struct SweepAndPrune {
+    /// The entities sorted by the left side of their bounding box.
+    entries: Vec<SapEntry>,
+    /// The index of every entity in the entries.
+    indices: HashMap<u32, usize>,
+    /// Width of the widest bounding box that has been added, a query has to look this far to the left of itself.
+    max_width: f32,
+    /// The number of removed entries still in the list.
+    removed: usize,
+}
+
+impl SweepAndPrune {
+    /// Create a new sweep and prune list from the entities.
+    pub fn new(data: Vec<EntityData>) -> SweepAndPrune {
+        let mut entries: Vec<_> = data
+            .iter()
+            .map(|e| SapEntry {
+                bb: e.2.clone(),
+                layers: e.0.collision_layers,
+                id: e.3,
+                removed: false,
+            })
+            .collect();
+        entries.sort_by(|a, b| a.bb.lx.partial_cmp(&b.bb.lx).unwrap());
+        let indices = entries.iter().enumerate().map(|(i, e)| (e.id, i)).collect();
+        let max_width = entries
+            .iter()
+            .map(|e| e.bb.rx - e.bb.lx)
+            .fold(0f32, f32::max);
+        SweepAndPrune {
+            entries,
+            indices,
+            max_width,
+            removed: 0,
+        }
+    }
+
+    /// Swap two entries and their indices.
+    fn swap(&mut self, i: usize, j: usize) {
+        self.entries.swap(i, j);
+        for index in [i, j] {
+            let entry = &self.entries[index];
+            // the id of a removed entry may belong to a newer entry
+            if !entry.removed {
+                self.indices.insert(entry.id, index);
+            }
+        }
+    }
+
+    /// Drop the removed entries from the list and fix the indices of the rest.
+    fn compact(&mut self) {
+        self.entries.retain(|e| !e.removed);
+        self.removed = 0;
+        self.indices.clear();
+        for (i, e) in self.entries.iter().enumerate() {
+            self.indices.insert(e.id, i);
+        }
+        self.max_width = self
+            .entries
+            .iter()
+            .map(|e| e.bb.rx - e.bb.lx)
+            .fold(0f32, f32::max);
+    }
+
+    /// Move an entry whose bounding box changed to its place in the sorted list.
+    fn resort(&mut self, mut i: usize) {
+        while i > 0 && self.entries[i - 1].bb.lx > self.entries[i].bb.lx {
+            self.swap(i - 1, i);
+            i -= 1;
+        }
+        while i + 1 < self.entries.len() && self.entries[i + 1].bb.lx < self.entries[i].bb.lx {
+            self.swap(i, i + 1);
+            i += 1;
+        }
+    }
+
+    /// Get the ids of the entities on any of the layers that could collide with a bounding box and pass a check.
+    fn query_sweep(&self, r: &AABB, layers: &LayerMask, check: impl Fn(&AABB) -> bool) -> Vec<u32> {
+        let start = self
+            .entries
+            .partition_point(|e| e.bb.lx < r.lx - self.max_width);
+        let end = self.entries.partition_point(|e| e.bb.lx <= r.rx);
+        self.entries[start..end.max(start)]
+            .iter()
+            .filter(|e| !e.removed && e.layers.intersects(layers) && check(&e.bb))
+            .map(|e| e.id)
+            .collect()
+    }
+}
+
+impl BroadPhase for SweepAndPrune {
+    fn insert(&mut self, new: &EntityData) {
+        self.remove(new.3);
+        self.entries.push(SapEntry {
+            bb: new.2.clone(),
+            layers: new.0.collision_layers,
+            id: new.3,
+            removed: false,
+        });
+        self.max_width = self.max_width.max(new.2.rx - new.2.lx);
+        let index = self.entries.len() - 1;
+        self.indices.insert(new.3, index);
+        self.resort(index);
+    }
+
+    fn update(&mut self, id: u32, bb: &AABB, _displacement: Vector2) -> bool {
+        let index = match self.indices.get(&id) {
+            Some(index) => *index,
+            None => return false,
+        };
+        self.entries[index].bb = bb.clone();
+        self.max_width = self.max_width.max(bb.rx - bb.lx);
+        self.resort(index);
+        true
+    }
+
+    fn remove(&mut self, id: u32) -> bool {
+        match self.indices.remove(&id) {
+            Some(index) => {
+                self.entries[index].removed = true;
+                self.removed += 1;
+                if self.removed * 2 > self.entries.len() {
+                    self.compact();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn get_layers(&self, id: u32) -> Option<LayerMask> {
+        self.indices.get(&id).map(|i| self.entries[*i].layers)
+    }
+
+    fn get_bounding_box(&self, id: u32) -> Option<AABB> {
+        self.indices.get(&id).map(|i| self.entries[*i].bb.clone())
+    }
+
+    fn get_ids(&self) -> Vec<u32> {
+        self.indices.keys().copied().collect()
+    }
+
+    fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    fn query_rect(&self, r: &AABB, layers: &LayerMask) -> Vec<u32> {
+        self.query_sweep(r, layers, |bb| bb.is_colliding(r))
+    }
+
+    fn query_point(&self, p: &Vector2, layers: &LayerMask) -> Vec<u32> {
+        let r = AABB {
+            lx: p.x,
+            rx: p.x,
+            ly: p.y,
+            ry: p.y,
+        };
+        self.query_sweep(&r, layers, |bb| contains_point(bb, p))
+    }
+
+    fn pairs(&self, filter: &dyn Fn(&LayerMask, &LayerMask) -> bool) -> Vec<(u32, u32)> {
+        let mut pairs = Vec::new();
+        for (i, a) in self.entries.iter().enumerate() {
+            if a.removed {
+                continue;
+            }
+            // everything after the first box that starts past the right side of this one is too far away
+            for b in self.entries[i + 1..]
+                .iter()
+                .take_while(|b| b.bb.lx <= a.bb.rx)
+            {
+                if !b.removed && a.bb.is_colliding(&b.bb) && filter(&a.layers, &b.layers) {
+                    pairs.push(ordered(a.id, b.id));
+                }
+            }
+        }
+        pairs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collider::{Collider, Shape};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn collider(layer: usize) -> Collider {
+        Collider {
+            shape: Shape::RectangeCollider {
+                size: Vector2::new(1f32, 1f32),
+            },
+            physics_collider: true,
+            collision_layers: LayerMask::layer(layer),
+            collision_mask: LayerMask::layer(layer),
+        }
+    }
+
+    /// The entities as a test keeps them, to check the broad phases against.
+    type Model = HashMap<u32, (AABB, usize)>;
+
+    fn random_box(rng: &mut StdRng) -> AABB {
+        let lx = rng.gen_range(0f32..500f32);
+        let ly = rng.gen_range(0f32..500f32);
+        // mostly boxes smaller than a cell, some that span many
+        let size = if rng.gen_bool(0.1) { 120f32 } else { 20f32 };
+        AABB {
+            lx,
+            ly,
+            rx: lx + rng.gen_range(1f32..size),
+            ry: ly + rng.gen_range(1f32..size),
+        }
+    }
+
+    fn brute_pairs(model: &Model) -> Vec<(u32, u32)> {
+        let mut pairs = Vec::new();
+        for (a, (bb_a, layer_a)) in model {
+            for (b, (bb_b, layer_b)) in model {
+                if a < b && layer_a == layer_b && bb_a.is_colliding(bb_b) {
+                    pairs.push((*a, *b));
+                }
+            }
+        }
+        pairs.sort_unstable();
+        pairs
+    }
+
+    fn brute_query(model: &Model, check: impl Fn(&AABB) -> bool) -> Vec<u32> {
+        let mut ids: Vec<_> = model
+            .iter()
+            .filter(|(_, (bb, layer))| *layer == 0 && check(bb))
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn sorted(mut ids: Vec<u32>) -> Vec<u32> {
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Check every broad phase against the model and against each other.
+    fn check(broad_phases: &[Box<dyn BroadPhase>], model: &Model, rng: &mut StdRng) {
+        let expected = brute_pairs(model);
+        let layers = LayerMask::layer(0);
+        for broad_phase in broad_phases {
+            assert_eq!(broad_phase.len(), model.len());
+            assert_eq!(
+                sorted(broad_phase.get_ids()),
+                sorted(model.keys().copied().collect())
+            );
+            for (id, (bb, _)) in model {
+                assert_eq!(broad_phase.get_bounding_box(*id).as_ref(), Some(bb));
+            }
+
+            // sorting keeps duplicates, so every pair has to be found exactly once
+            let mut pairs = broad_phase.pairs(&|a, b| a.intersects(b));
+            pairs.sort_unstable();
+            assert_eq!(pairs, expected);
+        }
+        for _ in 0..20 {
+            let r = random_box(rng);
+            let p = Vector2::new(rng.gen_range(0f32..520f32), rng.gen_range(0f32..520f32));
+            let expected_rect = brute_query(model, |bb| bb.is_colliding(&r));
+            let expected_point = brute_query(model, |bb| contains_point(bb, &p));
+            for broad_phase in broad_phases {
+                assert_eq!(sorted(broad_phase.query_rect(&r, &layers)), expected_rect);
+                assert_eq!(sorted(broad_phase.query_point(&p, &layers)), expected_point);
+            }
+        }
+    }
+
+    #[test]
+    fn broad_phases_match_brute_force() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let colliders = [collider(0), collider(1)];
+        let mut model = Model::new();
+        for id in 0..300 {
+            model.insert(id, (random_box(&mut rng), rng.gen_range(0..2)));
+        }
+        let data = || -> Vec<EntityData> {
+            model
+                .iter()
+                .map(|(id, (bb, layer))| (&colliders[*layer], Vector2::zero(), bb.clone(), *id))
+                .collect()
+        };
+        // cells smaller than most boxes, so pairs share several cells
+        let mut broad_phases: Vec<Box<dyn BroadPhase>> = vec![
+            Box::new(BVHTree::new(data())),
+            Box::new(SpatialHash::new(16f32, data())),
+            Box::new(SweepAndPrune::new(data())),
+        ];
+        check(&broad_phases, &model, &mut rng);
+
+        for round in 0..4 {
+            // small moves stay in the fat boxes of the bvh, large ones don't
+            let ids: Vec<u32> = model.keys().copied().collect();
+            for id in ids {
+                let entry = model.get_mut(&id).unwrap();
+                let d = if round % 2 == 0 { 2f32 } else { 60f32 };
+                let displacement = Vector2::new(rng.gen_range(-d..d), rng.gen_range(-d..d));
+                entry.0 = AABB {
+                    lx: entry.0.lx + displacement.x,
+                    rx: entry.0.rx + displacement.x,
+                    ly: entry.0.ly + displacement.y,
+                    ry: entry.0.ry + displacement.y,
+                };
+                for broad_phase in broad_phases.iter_mut() {
+                    assert!(broad_phase.update(id, &entry.0, displacement));
+                }
+            }
+            check(&broad_phases, &model, &mut rng);
+        }
+
+        // removing more than half compacts the sweep and prune list
+        let ids: Vec<u32> = model.keys().copied().collect();
+        for id in ids.into_iter().filter(|id| id % 5 != 0) {
+            model.remove(&id);
+            for broad_phase in broad_phases.iter_mut() {
+                assert!(broad_phase.remove(id));
+                assert!(!broad_phase.remove(id));
+            }
+        }
+        check(&broad_phases, &model, &mut rng);
+
+        // removed ids come back with new boxes and layers
+        for id in (0..300).filter(|id| id % 3 == 0) {
+            let layer = rng.gen_range(0..2);
+            let bb = random_box(&mut rng);
+            for broad_phase in broad_phases.iter_mut() {
+                broad_phase.insert(&(&colliders[layer], Vector2::zero(), bb.clone(), id));
+            }
+            model.insert(id, (bb, layer));
+        }
+        check(&broad_phases, &model, &mut rng);
+        assert!(!broad_phases[0].update(1, &random_box(&mut rng), Vector2::zero()));
+    }
+
+    #[test]
+    fn sweep_and_prune_compacts_removed_entries() {
+        let col = collider(0);
+        let data = (0..10)
+            .map(|id| {
+                let x = id as f32 * 10f32;
+                let bb = AABB {
+                    lx: x,
+                    rx: x + 15f32,
+                    ly: 0f32,
+                    ry: 1f32,
+                };
+                (&col, Vector2::zero(), bb, id)
+            })
+            .collect();
+        let mut sap = SweepAndPrune::new(data);
+        for id in 0..5 {
+            sap.remove(id);
+        }
+        // half of the entries are only flagged
+        assert_eq!((sap.entries.len(), sap.removed), (10, 5));
+        assert_eq!(
+            sap.pairs(&|_, _| true),
+            vec![(5, 6), (6, 7), (7, 8), (8, 9)]
+        );
+        sap.remove(5);
+        assert_eq!((sap.entries.len(), sap.removed), (4, 0));
+        assert_eq!(sap.pairs(&|_, _| true), vec![(6, 7), (7, 8), (8, 9)]);
+        assert_eq!(sap.indices[&6], 0);
+    }
+
+    #[test]
+    fn spatial_hash_keeps_huge_boxes_out_of_the_cells() {
+        let col = collider(0);
+        let small = AABB {
+            lx: 0f32,
+            rx: 10f32,
+            ly: 0f32,
+            ry: 10f32,
+        };
+        let huge = AABB {
+            lx: -1e30f32,
+            rx: 1e30f32,
+            ly: -1e30f32,
+            ry: 1e30f32,
+        };
+        let infinite = AABB {
+            lx: f32::NEG_INFINITY,
+            rx: f32::INFINITY,
+            ly: 0f32,
+            ry: 1f32,
+        };
+        let nan = AABB {
+            lx: f32::NAN,
+            rx: f32::NAN,
+            ly: 0f32,
+            ry: 1f32,
+        };
+        let mut grid = SpatialHash::new(
+            8f32,
+            vec![
+                (&col, Vector2::zero(), small.clone(), 0),
+                (&col, Vector2::zero(), huge.clone(), 1),
+                (&col, Vector2::zero(), infinite, 2),
+                (&col, Vector2::zero(), nan, 3),
+            ],
+        );
+        assert_eq!(grid.cells.len(), 4);
+        assert_eq!(
+            sorted(grid.oversized.iter().copied().collect()),
+            vec![1, 2, 3]
+        );
+        let mut pairs = grid.pairs(&|_, _| true);
+        pairs.sort_unstable();
+        assert_eq!(pairs, vec![(0, 1), (0, 2), (1, 2)]);
+        assert_eq!(
+            sorted(grid.query_point(&Vector2::new(5f32, 0.5f32), &LayerMask::ALL)),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            sorted(grid.query_rect(&huge, &LayerMask::ALL)),
+            vec![0, 1, 2]
+        );
+
+        // moving in and out of the grid
+        grid.update(1, &small, Vector2::zero());
+        grid.update(0, &huge, Vector2::zero());
+        assert_eq!(
+            sorted(grid.oversized.iter().copied().collect()),
+            vec![0, 2, 3]
+        );
+        assert_eq!(grid.cells.len(), 4);
+        grid.remove(0);
+        grid.remove(3);
+        assert_eq!(grid.oversized.iter().copied().collect::<Vec<_>>(), vec![2]);
+        assert_eq!(grid.pairs(&|_, _| true), vec![(1, 2)]);
+    }
+}
@@ -1,5 +1,8 @@
 use crate::collider;
+use crate::BvhData;
 use raylib::core::math::Vector2;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
 
 type EntityData<'a> = (
     &'a collider::Collider,
@@ -8,188 +11,473 @@ type EntityData<'a> = (
     u32,
 );
 
-/// Splits the list of entities into two lists by the mean position in the specified axis.
-fn split_at_mid(mut v: Vec<EntityData>, x_axis: bool) -> (Vec<EntityData>, Vec<EntityData>) {
-    let result: (&mut [EntityData], &mut EntityData, &mut [EntityData]);
+/// A payload waiting to be built into a tree, along with its bounding box and proxy.
+type BuildItem<T> = (collider::AABB, usize, T);
+
+/// The payload of the collision bvh tree, the id of the entity, the layers of its collider and its exact bounding box.
+/// The fruit holds the fattened box, queries filter by the exact one.
+pub type ColliderLeaf = (u32, collider::LayerMask, collider::AABB);
+
+/// Splits the list of items into two lists by the median center in the specified axis.
+fn split_at_mid<T>(
+    mut v: Vec<BuildItem<T>>,
+    x_axis: bool,
+) -> (Vec<BuildItem<T>>, Vec<BuildItem<T>>) {
     let half_size = (v.len() / 2usize) - 1;
+    v.select_nth_unstable_by(half_size, |item1, item2| {
+        get_center(&item1.0, x_axis)
+            .partial_cmp(&get_center(&item2.0, x_axis))
+            .unwrap()
+    });
+    let end = v.split_off(half_size + 1);
+    (v, end)
+}
+
+/// Splits the list of items at the median of the axis with the widest spread of centers.
+fn split_at_widest<T>(data: Vec<BuildItem<T>>) -> (Vec<BuildItem<T>>, Vec<BuildItem<T>>) {
+    let center = |bb: &collider::AABB| Vector2::new(get_center(bb, true), get_center(bb, false));
+    let first = center(&data[0].0);
+    let mut total_bb = collider::AABB {
+        lx: first.x,
+        rx: first.x,
+        ly: first.y,
+        ry: first.y,
+    };
+    for e in &data {
+        total_bb = total_bb.with_point(&center(&e.0));
+    }
+    split_at_mid(
+        data,
+        (total_bb.rx - total_bb.lx) > (total_bb.ry - total_bb.ly),
+    )
+}
+
+/// Nodes with more items than this build their children on separate threads.
+const PARALLEL_BUILD_SIZE: usize = 1024;
+/// Number of levels at the top of the tree that are refit on separate threads when shrinking.
+const PARALLEL_SHRINK_DEPTH: usize = 6;
 
+/// Space in pixels added around the bounding boxes of the entities in the collision tree, so that small movements don't change the tree.
+pub const AABB_MARGIN: f32 = 4f32;
+/// How many frames of movement the fat bounding boxes are stretched by when a fruit is reinserted.
+const DISPLACEMENT_MULTIPLIER: f32 = 2f32;
+
+/// Grow a bounding box by a margin, and further in the direction the fruit is moving.
+fn get_fat_box(bb: &collider::AABB, displacement: Vector2, margin: f32) -> collider::AABB {
+    let stretch = displacement * DISPLACEMENT_MULTIPLIER;
+    collider::AABB {
+        lx: bb.lx - margin + stretch.x.min(0f32),
+        rx: bb.rx + margin + stretch.x.max(0f32),
+        ly: bb.ly - margin + stretch.y.min(0f32),
+        ry: bb.ry + margin + stretch.y.max(0f32),
+    }
+}
+
+/// Number of buckets the centers are sorted into when looking for a surface area heuristic split.
+const SAH_BINS: usize = 16;
+
+/// How the items are split into two halves for each branch when building a bvh tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStrategy {
+    /// Split at the median center of the axis with the widest spread. Fast to build.
+    Median,
+    /// Split where the surface area heuristic is smallest, found by sorting the centers of the bounding boxes into bins.
+    /// Slower to build but the tree is cheaper to query, mostly when the items have very different sizes.
+    Sah,
+}
+
+impl Default for BuildStrategy {
+    fn default() -> Self {
+        BuildStrategy::Median
+    }
+}
+
+/// A plane splitting the centers of the items in one axis.
+struct SahSplit {
+    x_axis: bool,
+    min: f32,
+    scale: f32,
+    bin: usize,
+}
+
+impl SahSplit {
+    /// Check if a bounding box goes in the first half of the split.
+    fn contains(&self, bb: &collider::AABB) -> bool {
+        get_bin(bb, self.x_axis, self.min, self.scale) < self.bin
+    }
+}
+
+/// Get the center of a bounding box in one axis.
+fn get_center(bb: &collider::AABB, x_axis: bool) -> f32 {
     if x_axis {
-        result = v.select_nth_unstable_by(half_size, |vec1, vec2| {
-            vec1.1.x.partial_cmp(&vec2.1.x).unwrap()
-        });
+        (bb.lx + bb.rx) / 2f32
     } else {
-        result = v.select_nth_unstable_by(half_size, |vec1, vec2| {
-            vec1.1.y.partial_cmp(&vec2.1.y).unwrap()
-        });
+        (bb.ly + bb.ry) / 2f32
     }
-    let mut start = result.0.to_vec();
-    start.push(result.1.clone());
-    let end = result.2.to_vec();
-    assert_eq!(start.len() + end.len(), v.len());
-    (start, end)
 }
 
-/// A single node in the bounding volume hierarchy.
-/// The node can eighter contain children, or collision data.
-// for each node half of the bounding box data is shared with the parent node, so some effeciency improvements can be made here.
-#[derive(Debug, Clone, PartialEq)]
-pub enum Node {
-    Branch(collider::AABB, [Box<Node>; 2]),
-    Fruit(collider::AABB, u32, [bool; collider::LAYERS]),
-}
-
-impl Node {
-    /// Creates a node from some entities
-    fn new(mut data: Vec<EntityData>) -> Node {
-        // if there is only a single node, return a fruit node
-        if data.len() <= 1 {
-            let owned = data.remove(0);
-            Node::Fruit(owned.2, owned.3, owned.0.collision_layers)
-        } else {
-            // otherwise, split the list into two halves based on the axis with the widest position spread and create a branch node
-            let mut total_bb = data[0].2.clone();
-            for e in &data {
-                total_bb = total_bb.with_point(&e.1);
+/// Get the bin the center of a bounding box falls in.
+fn get_bin(bb: &collider::AABB, x_axis: bool, min: f32, scale: f32) -> usize {
+    (((get_center(bb, x_axis) - min) * scale) as usize).min(SAH_BINS - 1)
+}
+
+/// Half of the perimeter of a bounding box, the 2d version of the surface area.
+fn get_half_perimeter(bb: &collider::AABB) -> f32 {
+    (bb.rx - bb.lx) + (bb.ry - bb.ly)
+}
+
+/// Add a bounding box to a bin that may be empty.
+fn add_to_bin(
+    bin: &Option<(usize, collider::AABB)>,
+    count: usize,
+    bb: &collider::AABB,
+) -> (usize, collider::AABB) {
+    match bin {
+        Some((n, total)) => (n + count, total.get_union(bb)),
+        None => (count, bb.clone()),
+    }
+}
+
+/// Find the split with the lowest surface area heuristic cost in either axis.
+/// The cost of a split is the half perimeter of each side times the number of items in it.
+/// Returns None if all of the centers are in the same place.
+fn find_sah_split<T>(data: &[BuildItem<T>]) -> Option<SahSplit> {
+    let mut best: Option<(f32, SahSplit)> = None;
+    for &x_axis in &[true, false] {
+        let (min, max) = data
+            .iter()
+            .map(|e| get_center(&e.0, x_axis))
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), c| {
+                (min.min(c), max.max(c))
+            });
+        if max <= min {
+            continue;
+        }
+        let scale = SAH_BINS as f32 / (max - min);
+
+        let mut bins: Vec<Option<(usize, collider::AABB)>> = vec![None; SAH_BINS];
+        for e in data {
+            let i = get_bin(&e.0, x_axis, min, scale);
+            bins[i] = Some(add_to_bin(&bins[i], 1, &e.0));
+        }
+
+        // the cost of everything to the right of each bin, built from the right
+        let mut right_costs = vec![None; SAH_BINS];
+        let mut right: Option<(usize, collider::AABB)> = None;
+        for i in (1..SAH_BINS).rev() {
+            if let Some((n, bb)) = &bins[i] {
+                right = Some(add_to_bin(&right, *n, bb));
             }
-            let (first_half, second_half) = split_at_mid(
-                data,
-                (total_bb.rx - total_bb.lx) > (total_bb.ry - total_bb.ly),
-            );
-            let node1 = Node::new(first_half);
-            let node2 = Node::new(second_half);
-            let bb1 = match node1 {
-                Node::Branch(ref bb, _) => bb,
-                Node::Fruit(ref bb, _, _) => bb,
-            };
-            let bb2 = match node2 {
-                Node::Branch(ref bb, _) => bb,
-                Node::Fruit(ref bb, _, _) => bb,
-            };
-            let total_bb = bb1.get_union(bb2);
-            Node::Branch(total_bb, [Box::new(node1), Box::new(node2)])
+            right_costs[i] = right
+                .as_ref()
+                .map(|(n, bb)| *n as f32 * get_half_perimeter(bb));
         }
-    }
 
-    /// Shrink the bounding box to the smallest bounding box that contains all the entities in the node.
-    fn shrink(&mut self) -> &collider::AABB {
-        match self {
-            Node::Branch(bb, children) => {
-                let mut children_iter = children.iter_mut();
-                if let Some(first) = children_iter.next() {
-                    *bb = first.shrink().clone();
-                    for c in children_iter {
-                        *bb = bb.get_union(c.shrink());
-                    }
-                };
-                bb
+        let mut left: Option<(usize, collider::AABB)> = None;
+        for i in 1..SAH_BINS {
+            if let Some((n, bb)) = &bins[i - 1] {
+                left = Some(add_to_bin(&left, *n, bb));
+            }
+            if let (Some((n, bb)), Some(right_cost)) = (&left, right_costs[i]) {
+                let cost = *n as f32 * get_half_perimeter(bb) + right_cost;
+                if best
+                    .as_ref()
+                    .map_or(true, |(best_cost, _)| cost < *best_cost)
+                {
+                    best = Some((
+                        cost,
+                        SahSplit {
+                            x_axis,
+                            min,
+                            scale,
+                            bin: i,
+                        },
+                    ));
+                }
             }
-            Node::Fruit(bb, _, _) => bb,
         }
     }
+    best.map(|(_, split)| split)
+}
+
+/// A single node in the bounding volume hierarchy.
+/// The node can eighter contain children, or a payload.
+/// Branches refer to their children by their index in the tree.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node<T> {
+    Branch(collider::AABB, [usize; 2]),
+    Fruit(collider::AABB, T),
+}
 
-    /// Get the ids of all of the entities under this node.
-    fn get_children_id(&self) -> Vec<u32> {
-        let mut sum_vec = Vec::new();
+impl<T> Node<T> {
+    /// Get the bounding box of the node.
+    fn get_bounding_box(&self) -> &collider::AABB {
         match self {
-            Node::Branch(_, children) => {
-                for c in children {
-                    sum_vec.append(&mut c.get_children_id());
-                }
-            }
-            Node::Fruit(_, other_data, _) => {
-                sum_vec.push(*other_data);
-            }
+            Node::Branch(bb, _) => bb,
+            Node::Fruit(bb, _) => bb,
         }
-        sum_vec
     }
 
-    /// Get the entities under this node.
-    fn get_children(&self) -> Vec<&Node> {
-        let mut sum_vec = Vec::new();
-        if let Node::Branch(_, children) = self {
-            for c in children {
-                sum_vec.append(&mut c.get_children());
-            }
+    /// A node that takes the place of a removed node.
+    fn empty() -> Node<T> {
+        Node::Branch(
+            collider::AABB {
+                lx: 0f32,
+                rx: 0f32,
+                ly: 0f32,
+                ry: 0f32,
+            },
+            [0, 0],
+        )
+    }
+}
+
+/// A node stored in the tree along with the branch that holds it.
+#[derive(Debug, Clone)]
+struct Slot<T> {
+    node: Node<T>,
+    parent: Option<usize>,
+    /// The proxy of the fruit, unused for branches.
+    proxy: usize,
+}
+
+/// Builds the nodes for some items and returns the index of the root.
+/// Children are pushed before their parents, so the root of the new nodes is always the last one.
+fn build_nodes<T: Send>(
+    mut data: Vec<BuildItem<T>>,
+    strategy: BuildStrategy,
+    nodes: &mut Vec<Slot<T>>,
+) -> usize {
+    // if there is only a single item, return a fruit node
+    if data.len() <= 1 {
+        let (bb, proxy, payload) = data.remove(0);
+        nodes.push(Slot {
+            node: Node::Fruit(bb, payload),
+            parent: None,
+            proxy,
+        });
+        return nodes.len() - 1;
+    }
+    let (first_half, second_half) = match strategy {
+        BuildStrategy::Median => split_at_widest(data),
+        BuildStrategy::Sah => match find_sah_split(&data) {
+            Some(split) => data.into_iter().partition(|e| split.contains(&e.0)),
+            // all of the centers are in the same place, so there is nothing to gain over a median split
+            None => split_at_widest(data),
+        },
+    };
+    // large halves are built on separate threads, small ones are not worth the overhead
+    let children = if first_half.len().max(second_half.len()) > PARALLEL_BUILD_SIZE {
+        let (first, second) = rayon::join(
+            || {
+                let mut nodes = Vec::new();
+                build_nodes(first_half, strategy, &mut nodes);
+                nodes
+            },
+            || {
+                let mut nodes = Vec::new();
+                build_nodes(second_half, strategy, &mut nodes);
+                nodes
+            },
+        );
+        [append_nodes(nodes, first), append_nodes(nodes, second)]
+    } else {
+        [
+            build_nodes(first_half, strategy, nodes),
+            build_nodes(second_half, strategy, nodes),
+        ]
+    };
+    let bb = nodes[children[0]]
+        .node
+        .get_bounding_box()
+        .get_union(nodes[children[1]].node.get_bounding_box());
+    nodes.push(Slot {
+        node: Node::Branch(bb, children),
+        parent: None,
+        proxy: 0,
+    });
+    let index = nodes.len() - 1;
+    for &child in &children {
+        nodes[child].parent = Some(index);
+    }
+    index
+}
+
+/// Move nodes that were built separately to the end of another list of nodes, returning the index of their root.
+fn append_nodes<T>(nodes: &mut Vec<Slot<T>>, other: Vec<Slot<T>>) -> usize {
+    let offset = nodes.len();
+    nodes.extend(other.into_iter().map(|mut slot| {
+        slot.parent = slot.parent.map(|parent| parent + offset);
+        if let Node::Branch(_, children) = &mut slot.node {
+            children[0] += offset;
+            children[1] += offset;
         }
-        sum_vec.push(self);
-        sum_vec
+        slot
+    }));
+    nodes.len() - 1
+}
+
+/// Get the squared distance from a point to the closest point of a bounding box, zero if it is inside.
+fn get_dist_sqr(bb: &collider::AABB, p: &Vector2) -> f32 {
+    let dx = (bb.lx - p.x).max(0f32).max(p.x - bb.rx);
+    let dy = (bb.ly - p.y).max(0f32).max(p.y - bb.ry);
+    dx * dx + dy * dy
+}
+
+/// Check if a point is inside a bounding box.
+fn collide_point(bb: &collider::AABB, p: &Vector2) -> bool {
+    bb.lx < p.x && bb.rx > p.x && bb.ly < p.y && bb.ry > p.y
+}
+
+/// Check if a bounding box is within a radius of a point.
+fn collide_circle(bb: &collider::AABB, c: &(Vector2, f32)) -> bool {
+    get_dist_sqr(bb, &c.0) <= c.1 * c.1
+}
+
+/// A node in the queue of a nearest neighbour search, the closest node is popped first.
+/// Fruits are queued a second time with the distance to their payload before they are returned.
+struct NearestEntry(f32, usize, bool);
+
+impl PartialEq for NearestEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
     }
+}
 
-    /// Traverse the tree based on a custom collision callback. Callback and current state allows state to be held as the tree is traversed.
-    fn traverse<'a, T: Clone, K>(
-        &'a self,
-        p: &K,
-        layers: &[bool; collider::LAYERS],
-        collision_callback: fn(&collider::AABB, &K) -> bool,
-        callback: &mut impl FnMut(&'a Node, T) -> T,
-        current_state: T,
-    ) {
-        match self {
-            Node::Branch(bb, children) => {
-                if collision_callback(bb, &p) {
-                    let next_state = callback(self, current_state);
-                    for child in children {
-                        child.traverse(p, layers, collision_callback, callback, next_state.clone());
-                    }
-                }
-            }
-            Node::Fruit(bb, _, l) => {
-                let mut contains_layer = false;
-                for (layer1, layer2) in l.iter().zip(layers) {
-                    if *layer1 && *layer2 {
-                        contains_layer = true;
-                        break;
-                    }
-                }
-                if contains_layer && collision_callback(bb, &p) {
-                    callback(self, current_state);
-                }
-            }
+impl Eq for NearestEntry {}
+
+impl PartialOrd for NearestEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NearestEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed so the binary heap pops the smallest distance
+        other.0.partial_cmp(&self.0).unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Where a ray hit an entity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayHit {
+    pub id: u32,
+    pub point: Vector2,
+    /// The normal of the surface that was hit.
+    pub normal: Vector2,
+    /// The distance from the origin of the ray to the hit point.
+    pub distance: f32,
+}
+
+/// Where a shape moving through the tree hit an entity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShapeHit {
+    pub id: u32,
+    /// The fraction of the path the shape can move before touching the entity.
+    pub time: f32,
+    /// The position of the shape when it touches the entity.
+    pub position: Vector2,
+    /// The normal of the surface that was hit, pointing towards the shape.
+    pub normal: Vector2,
+}
+
+/// A bounding volume hierarchy over any kind of payload, with 2 subdivisions.
+/// It can be built top-down from a list of items and is then kept up to date as they move.
+/// Every payload gets a proxy when it is added, which is used to move or remove it later.
+/// The fruits can hold fattened bounding boxes so that moving payloads only need to be reinserted when they leave them.
+/// Queries take a filter that decides which payloads are returned.
+pub struct Bvh<T> {
+    /// The nodes of the tree, mostly in depth first order.
+    nodes: Vec<Slot<T>>,
+    /// Indices of removed nodes that can be reused.
+    free: Vec<usize>,
+    root: Option<usize>,
+    /// The index of the fruit of every proxy.
+    proxies: Vec<Option<usize>>,
+    /// Proxies of removed payloads that can be reused.
+    free_proxies: Vec<usize>,
+    /// Space added around the bounding boxes of the fruits.
+    margin: f32,
+    /// Number of fruits inserted since the nodes were last put in depth first order.
+    inserted: usize,
+}
+
+impl<T> Default for Bvh<T> {
+    fn default() -> Self {
+        Bvh::new()
+    }
+}
+
+impl<T> Bvh<T> {
+    /// Create an empty tree that stores the bounding boxes as they are given.
+    pub fn new() -> Bvh<T> {
+        Bvh::with_margin(0f32)
+    }
+
+    /// Create an empty tree that grows the bounding boxes by a margin, so that small movements don't change the tree.
+    pub fn with_margin(margin: f32) -> Bvh<T> {
+        Bvh {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            root: None,
+            proxies: Vec::new(),
+            free_proxies: Vec::new(),
+            margin,
+            inserted: 0,
         }
     }
 
-    /// Traverse any nodes that collide with a point.
-    fn traverse_point<'a, T: Clone>(
-        &'a self,
-        p: &Vector2,
-        layers: &[bool; collider::LAYERS],
-        callback: &mut impl FnMut(&'a Node, T) -> T,
-        current_state: T,
-    ) {
-        fn collide_point(bb: &collider::AABB, p: &Vector2) -> bool {
-            bb.lx < p.x && bb.rx > p.x && bb.ly < p.y && bb.ry > p.y
+    /// Get the number of payloads in the tree.
+    pub fn len(&self) -> usize {
+        self.proxies.len() - self.free_proxies.len()
+    }
+
+    /// Check if the tree has no payloads.
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// Get the payload of a proxy.
+    pub fn get(&self, proxy: usize) -> Option<&T> {
+        match self.get_fruit(proxy).map(|leaf| self.get_node(leaf)) {
+            Some(Node::Fruit(_, payload)) => Some(payload),
+            _ => None,
         }
-        self.traverse(p, layers, collide_point, callback, current_state);
     }
 
-    /// Traverse any nodes that collide with a bounding box.
-    fn traverse_rect<'a, T: Clone>(
-        &'a self,
-        r: &collider::AABB,
-        layers: &[bool; collider::LAYERS],
-        callback: &mut impl FnMut(&'a Node, T) -> T,
-        current_state: T,
-    ) {
-        fn collide_rect(bb: &collider::AABB, bb2: &collider::AABB) -> bool {
-            bb.is_colliding(bb2)
+    /// Get the payload of a proxy mutably, changing it doesn't move it in the tree.
+    pub fn get_mut(&mut self, proxy: usize) -> Option<&mut T> {
+        let leaf = self.get_fruit(proxy)?;
+        match &mut self.nodes[leaf].node {
+            Node::Fruit(_, payload) => Some(payload),
+            _ => None,
         }
-        self.traverse(r, layers, collide_rect, callback, current_state);
-    }
-
-    /// Find any entities that collide with a point.
-    fn query_point(&self, p: &Vector2, layers: &[bool; collider::LAYERS]) -> Option<Vec<u32>> {
-        let mut result: Option<Vec<u32>> = None;
-        self.traverse_point(
-            p,
-            layers,
-            &mut |node, _| match node {
-                Node::Branch(_, _) => (),
-                Node::Fruit(_, other_data, _) => {
-                    if let Some(ref mut result_vec) = result {
-                        result_vec.push(*other_data);
-                    } else {
-                        result = Some(vec![*other_data]);
-                    }
+    }
+
+    /// Get every node in the tree, children before their parents.
+    pub fn get_nodes(&self) -> Vec<&Node<T>> {
+        let mut result = Vec::new();
+        if let Some(root) = self.root {
+            self.collect_nodes(root, &mut result);
+        }
+        result
+    }
+
+    /// Get all the payloads that collide with a point.
+    pub fn query_point(&self, p: &Vector2, filter: impl Fn(&T) -> bool) -> Vec<&T> {
+        self.debug_query_point(p, filter).0
+    }
+
+    /// Get all the payloads that collide with a bounding box.
+    pub fn query_rect(&self, r: &collider::AABB, filter: impl Fn(&T) -> bool) -> Vec<&T> {
+        let mut result = Vec::new();
+        self.traverse_rect(
+            r,
+            &filter,
+            &mut |node, _| {
+                if let Node::Fruit(_, payload) = node {
+                    result.push(payload);
                 }
             },
             (),
@@ -197,248 +485,1159 @@ impl Node {
         result
     }
 
-    /// Find any entities that collide with a point, and what nodes contain those enties. Useful for debugging purposes.
-    fn debug_query_point<'a>(
-        &'a self,
+    /// Get all the payloads whose bounding box is within a radius of a point.
+    pub fn query_circle(
+        &self,
+        center: &Vector2,
+        radius: f32,
+        filter: impl Fn(&T) -> bool,
+    ) -> Vec<&T> {
+        let mut result = Vec::new();
+        if let Some(root) = self.root {
+            self.traverse(
+                root,
+                &(*center, radius),
+                &filter,
+                collide_circle,
+                &mut |node, _| {
+                    if let Node::Fruit(_, payload) = node {
+                        result.push(payload);
+                    }
+                },
+                (),
+            );
+        }
+        result
+    }
+
+    /// Get the k payloads closest to a point, closest first.
+    /// The distance is measured to the closest point of the bounding boxes.
+    pub fn nearest(&self, p: &Vector2, k: usize, filter: impl Fn(&T) -> bool) -> Vec<&T> {
+        self.nearest_by(p, k, filter, |_| 0f32)
+    }
+
+    /// Get the k payloads closest to a point, closest first.
+    /// The squared distance to a payload is the larger of the squared distance to its bounding box and the one from the callback,
+    /// so payloads that are smaller than their fat box can be measured exactly.
+    pub fn nearest_by(
+        &self,
         p: &Vector2,
-        layers: &[bool; collider::LAYERS],
-    ) -> (Option<Vec<u32>>, Vec<(&Node, i32)>) {
-        let mut result: (Option<Vec<u32>>, Vec<(&'a Node, i32)>) = (None, Vec::new());
-        self.traverse_point(
-            p,
-            layers,
-            &mut |node, depth| {
-                result.1.push((&node, depth));
-                match node {
-                    Node::Branch(_, _) => {}
-                    Node::Fruit(_, other_data, _) => {
-                        if let Some(ref mut result_vec) = result.0 {
-                            result_vec.push(*other_data);
-                        } else {
-                            result.0 = Some(vec![*other_data]);
-                        }
+        k: usize,
+        filter: impl Fn(&T) -> bool,
+        dist_sqr: impl Fn(&T) -> f32,
+    ) -> Vec<&T> {
+        let root = match self.root {
+            Some(root) => root,
+            None => return Vec::new(),
+        };
+        let mut result = Vec::new();
+        let mut queue = BinaryHeap::new();
+        queue.push(NearestEntry(
+            get_dist_sqr(self.get_bounding_box(root), p),
+            root,
+            false,
+        ));
+        // a node is never farther than what is under it, so payloads come out of the queue in order
+        while let Some(NearestEntry(dist, index, measured)) = queue.pop() {
+            if result.len() >= k {
+                break;
+            }
+            match self.get_node(index) {
+                Node::Branch(_, children) => {
+                    for &c in children {
+                        queue.push(NearestEntry(
+                            get_dist_sqr(self.get_bounding_box(c), p),
+                            c,
+                            false,
+                        ));
                     }
                 }
-                depth + 1
-            },
-            0i32,
-        );
+                Node::Fruit(_, payload) => {
+                    if measured {
+                        result.push(payload);
+                    } else if filter(payload) {
+                        queue.push(NearestEntry(dist.max(dist_sqr(payload)), index, true));
+                    }
+                }
+            }
+        }
         result
     }
 
-    /// Find any entities that collide with a bounding box.
-    fn query_rect(
+    /// Get all the payloads that collide with a point, along with the nodes that were visited and their depth.
+    pub fn debug_query_point(
+        &self,
+        p: &Vector2,
+        filter: impl Fn(&T) -> bool,
+    ) -> (Vec<&T>, Vec<(&Node<T>, i32)>) {
+        let mut result = (Vec::new(), Vec::new());
+        if let Some(root) = self.root {
+            self.traverse(
+                root,
+                p,
+                &filter,
+                collide_point,
+                &mut |node, depth| {
+                    push_debug_node(&mut result, node, depth);
+                    depth + 1
+                },
+                0i32,
+            );
+        }
+        result
+    }
+
+    /// Get all the payloads that collide with a bounding box, along with the nodes that were visited and their depth.
+    pub fn debug_query_rect(
         &self,
         r: &collider::AABB,
-        layers: &[bool; collider::LAYERS],
-    ) -> Option<Vec<u32>> {
-        let mut result: Option<Vec<u32>> = None;
+        filter: impl Fn(&T) -> bool,
+    ) -> (Vec<&T>, Vec<(&Node<T>, i32)>) {
+        let mut result = (Vec::new(), Vec::new());
         self.traverse_rect(
             r,
-            layers,
-            &mut |node, _| match node {
-                Node::Branch(_, _) => (),
-                Node::Fruit(_, other_data, _) => {
-                    if let Some(ref mut result_vec) = result {
-                        result_vec.push(*other_data);
-                    } else {
-                        result = Some(vec![*other_data]);
-                    }
-                }
+            &filter,
+            &mut |node, depth| {
+                push_debug_node(&mut result, node, depth);
+                depth + 1
             },
-            (),
+            0i32,
         );
         result
     }
 
-    /// Find any entities that collide with a bounding box, and what nodes contain those enties. Useful for debugging purposes.
-    fn debug_query_rect<'a>(
-        &'a self,
-        r: &collider::AABB,
-        layers: &[bool; collider::LAYERS],
-    ) -> (Option<Vec<u32>>, Vec<(&Node, i32)>) {
-        let mut result: (Option<Vec<u32>>, Vec<(&'a Node, i32)>) = (None, Vec::new());
-        self.traverse_rect(
-            r,
-            layers,
-            &mut |node, depth| {
-                result.1.push((&node, depth));
-                match node {
-                    Node::Branch(_, _) => {}
-                    Node::Fruit(_, other_data, _) => {
-                        if let Some(ref mut result_vec) = result.0 {
-                            result_vec.push(*other_data);
+    /// Visit the payloads whose bounding box is hit by a ray, starting with the closest branch.
+    /// The direction should be normalized so the distances are in pixels.
+    /// The callback gets the payload and the max distance, and returns the new max distance so that farther branches can be skipped.
+    pub fn raycast(
+        &self,
+        origin: &Vector2,
+        direction: &Vector2,
+        max_distance: f32,
+        filter: impl Fn(&T) -> bool,
+        mut callback: impl FnMut(&T, f32) -> f32,
+    ) {
+        let root = match self.root {
+            Some(root) => root,
+            None => return,
+        };
+        if self
+            .get_bounding_box(root)
+            .raycast(origin, direction, max_distance)
+            .is_none()
+        {
+            return;
+        }
+        let mut max_distance = max_distance;
+        self.raycast_node(
+            root,
+            origin,
+            direction,
+            &filter,
+            &mut max_distance,
+            &mut callback,
+        );
+    }
+
+    /// Get every pair of payloads whose bounding boxes collide and that pass the filter, each pair once.
+    /// The tree is traversed against itself, every pair is only looked for under the branch where the paths to its two fruits split.
+    pub fn overlapping_pairs(&self, filter: impl Fn(&T, &T) -> bool) -> Vec<(&T, &T)> {
+        let mut pairs = Vec::new();
+        let mut branches: Vec<usize> = self.root.into_iter().collect();
+        let mut stack = Vec::new();
+        while let Some(index) = branches.pop() {
+            let children = match self.get_node(index) {
+                Node::Branch(_, children) => children,
+                Node::Fruit(_, _) => continue,
+            };
+            branches.extend_from_slice(children);
+            // pairs with one fruit under each child
+            stack.push((children[0], children[1]));
+            while let Some((a, b)) = stack.pop() {
+                if !self
+                    .get_bounding_box(a)
+                    .is_colliding(self.get_bounding_box(b))
+                {
+                    continue;
+                }
+                match (self.get_node(a), self.get_node(b)) {
+                    (Node::Fruit(_, payload_a), Node::Fruit(_, payload_b)) => {
+                        if filter(payload_a, payload_b) {
+                            pairs.push((payload_a, payload_b));
+                        }
+                    }
+                    (Node::Branch(_, children_a), Node::Fruit(_, _)) => {
+                        stack.push((children_a[0], b));
+                        stack.push((children_a[1], b));
+                    }
+                    (Node::Fruit(_, _), Node::Branch(_, children_b)) => {
+                        stack.push((a, children_b[0]));
+                        stack.push((a, children_b[1]));
+                    }
+                    // the larger branch is split first so the boxes that are compared stay about the same size
+                    (Node::Branch(bb_a, children_a), Node::Branch(bb_b, children_b)) => {
+                        if get_half_perimeter(bb_a) >= get_half_perimeter(bb_b) {
+                            stack.push((children_a[0], b));
+                            stack.push((children_a[1], b));
                         } else {
-                            result.0 = Some(vec![*other_data]);
+                            stack.push((a, children_b[0]));
+                            stack.push((a, children_b[1]));
                         }
                     }
                 }
-                depth.clone() + 1
-            },
-            0i32,
-        );
-        result
+            }
+        }
+        pairs
     }
 
-    /// Update a bounding box within the tree.
-    /// Note: This does will only grow the bounding boxes of the parent nodes, so it it important to call shrink regularly to maintain optimal bounding boxes for branch nodes.
-    fn update(&mut self, old: (&collider::AABB, u32), new: (&collider::AABB, u32)) -> bool {
-        match self {
+    /// Add a payload to the tree and get its proxy.
+    pub fn insert(&mut self, bb: &collider::AABB, payload: T) -> usize {
+        let proxy = match self.free_proxies.pop() {
+            Some(proxy) => proxy,
+            None => {
+                self.proxies.push(None);
+                self.proxies.len() - 1
+            }
+        };
+        let fat_bb = get_fat_box(bb, Vector2::new(0f32, 0f32), self.margin);
+        self.insert_leaf(proxy, fat_bb, payload);
+        proxy
+    }
+
+    /// Move a payload to a new bounding box, returns false if the proxy is not in the tree.
+    /// Nothing changes while the bounding box stays inside the fat box of the fruit, otherwise it is reinserted
+    /// with a fat box stretched in the direction of the displacement so that it can keep moving that way for a while.
+    pub fn update(&mut self, proxy: usize, bb: &collider::AABB, displacement: Vector2) -> bool {
+        let leaf = match self.get_fruit(proxy) {
+            Some(leaf) => leaf,
+            None => return false,
+        };
+        if self.get_bounding_box(leaf).contains(bb) {
+            return true;
+        }
+        let payload = self.remove_leaf(leaf);
+        let fat_bb = get_fat_box(bb, displacement, self.margin);
+        self.insert_leaf(proxy, fat_bb, payload);
+        true
+    }
+
+    /// Take a payload out of the tree, returns None if the proxy is not in the tree.
+    pub fn remove(&mut self, proxy: usize) -> Option<T> {
+        let leaf = self.get_fruit(proxy)?;
+        self.proxies[proxy] = None;
+        self.free_proxies.push(proxy);
+        Some(self.remove_leaf(leaf))
+    }
+
+    /// Rewrite the nodes in depth first order, so that every branch is followed by its first child and queries move forward through memory.
+    /// Removed nodes are dropped. This happens on its own after as many fruits have been reinserted as there are payloads.
+    pub fn optimize_layout(&mut self) {
+        self.inserted = 0;
+        self.free.clear();
+        let root = match self.root {
+            Some(root) => root,
+            None => {
+                self.nodes.clear();
+                return;
+            }
+        };
+        let mut old_nodes = std::mem::take(&mut self.nodes);
+        self.nodes.reserve(self.len() * 2);
+        // (old index, parent in the new nodes and which child of it this is)
+        let mut stack = vec![(root, None)];
+        while let Some((old, parent)) = stack.pop() {
+            let index = self.nodes.len();
+            let proxy = old_nodes[old].proxy;
+            let node = std::mem::replace(&mut old_nodes[old].node, Node::empty());
+            match &node {
+                Node::Branch(_, children) => {
+                    // the first child is popped next so it ends up right after this branch
+                    stack.push((children[1], Some((index, 1))));
+                    stack.push((children[0], Some((index, 0))));
+                }
+                Node::Fruit(_, _) => self.proxies[proxy] = Some(index),
+            }
+            match parent {
+                Some((parent, child)) => {
+                    if let Node::Branch(_, children) = &mut self.nodes[parent].node {
+                        children[child] = index;
+                    }
+                }
+                None => self.root = Some(index),
+            }
+            self.nodes.push(Slot {
+                node,
+                parent: parent.map(|(parent, _)| parent),
+                proxy,
+            });
+        }
+    }
+
+    /// Get the index of the fruit of a proxy.
+    fn get_fruit(&self, proxy: usize) -> Option<usize> {
+        self.proxies.get(proxy).copied().flatten()
+    }
+
+    /// Get a node by its index.
+    fn get_node(&self, index: usize) -> &Node<T> {
+        &self.nodes[index].node
+    }
+
+    /// Get the bounding box of a node by its index.
+    fn get_bounding_box(&self, index: usize) -> &collider::AABB {
+        self.get_node(index).get_bounding_box()
+    }
+
+    /// Add the nodes under a node to the list, children before their parents.
+    fn collect_nodes<'a>(&'a self, index: usize, result: &mut Vec<&'a Node<T>>) {
+        let node = self.get_node(index);
+        if let Node::Branch(_, children) = node {
+            for &c in children {
+                self.collect_nodes(c, result);
+            }
+        }
+        result.push(node);
+    }
+
+    /// Traverse the tree based on a custom collision callback. Callback and current state allows state to be held as the tree is traversed.
+    fn traverse<'a, S: Clone, K>(
+        &'a self,
+        index: usize,
+        p: &K,
+        filter: &impl Fn(&T) -> bool,
+        collision_callback: fn(&collider::AABB, &K) -> bool,
+        callback: &mut impl FnMut(&'a Node<T>, S) -> S,
+        current_state: S,
+    ) {
+        let node = self.get_node(index);
+        match node {
             Node::Branch(bb, children) => {
-                if bb.contains(old.0) {
-                    for c in children {
-                        if c.update(old, new) {
-                            return true;
-                        }
+                if collision_callback(bb, &p) {
+                    let next_state = callback(node, current_state);
+                    for &child in children {
+                        self.traverse(
+                            child,
+                            p,
+                            filter,
+                            collision_callback,
+                            callback,
+                            next_state.clone(),
+                        );
                     }
-                    *bb = bb.get_union(new.0);
                 }
             }
-            Node::Fruit(bb, id, _) => {
-                if *id == old.1 {
-                    *bb = new.0.clone();
-                    return true;
+            Node::Fruit(bb, payload) => {
+                if filter(payload) && collision_callback(bb, &p) {
+                    callback(node, current_state);
                 }
             }
         }
-        false
     }
 
-    /// Remove a entity from the tree.
-    fn delete(&mut self, old: u32) -> (bool, bool) {
-        match self {
+    /// Traverse any nodes that collide with a bounding box.
+    fn traverse_rect<'a, S: Clone>(
+        &'a self,
+        r: &collider::AABB,
+        filter: &impl Fn(&T) -> bool,
+        callback: &mut impl FnMut(&'a Node<T>, S) -> S,
+        current_state: S,
+    ) {
+        fn collide_rect(bb: &collider::AABB, bb2: &collider::AABB) -> bool {
+            bb.is_colliding(bb2)
+        }
+        if let Some(root) = self.root {
+            self.traverse(root, r, filter, collide_rect, callback, current_state);
+        }
+    }
+
+    /// Visit the fruits under a node whose bounding box is hit by a ray, starting with the closest branch.
+    fn raycast_node(
+        &self,
+        index: usize,
+        origin: &Vector2,
+        direction: &Vector2,
+        filter: &impl Fn(&T) -> bool,
+        max_distance: &mut f32,
+        callback: &mut impl FnMut(&T, f32) -> f32,
+    ) {
+        match self.get_node(index) {
             Node::Branch(_, children) => {
-                let result = children[0].delete(old);
-                if result.1 {
-                    if result.0 {
-                        *self = *children[1].clone();
+                let mut hits: Vec<_> = children
+                    .iter()
+                    .filter_map(|&c| {
+                        self.get_bounding_box(c)
+                            .raycast(origin, direction, *max_distance)
+                            .map(|distance| (distance, c))
+                    })
+                    .collect();
+                hits.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+                for (distance, child) in hits {
+                    // a closer hit may have been found in the other child
+                    if distance <= *max_distance {
+                        self.raycast_node(child, origin, direction, filter, max_distance, callback);
                     }
-                    return (false, true);
                 }
-                let result = children[1].delete(old);
-                if result.1 {
-                    if result.0 {
-                        *self = *children[0].clone();
-                    }
-                    return (false, true);
+            }
+            Node::Fruit(_, payload) => {
+                if filter(payload) {
+                    *max_distance = callback(payload, *max_distance);
                 }
             }
-            Node::Fruit(_, id, _) => {
-                if *id == old {
-                    return (true, true);
+        }
+    }
+
+    /// Store a node, reusing the space of a removed node if there is one.
+    fn allocate(&mut self, node: Node<T>, parent: Option<usize>, proxy: usize) -> usize {
+        let slot = Slot {
+            node,
+            parent,
+            proxy,
+        };
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = slot;
+                index
+            }
+            None => {
+                self.nodes.push(slot);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    /// Replace a child of a branch with another node.
+    fn replace_child(&mut self, parent: usize, old: usize, new: usize) {
+        if let Node::Branch(_, children) = &mut self.nodes[parent].node {
+            for c in children.iter_mut() {
+                if *c == old {
+                    *c = new;
                 }
             }
         }
-        (false, false)
     }
 
-    /// Create a new entity in the tree.
-    fn insert(&mut self, new: &(&collider::Collider, Vector2, collider::AABB, u32)) {
-        let new_fruit_bb = new.0.get_bounding_box(&new.1);
-        match self {
-            Node::Branch(bb, children) => {
-                *bb = bb.get_union(&new_fruit_bb);
-                let mut best_dist = match &*children[0] {
-                    Node::Branch(bb2, _) => bb2.get_dist(&new.2),
-                    Node::Fruit(bb2, _, _) => bb2.get_dist(&new.2),
-                };
-                let (ref mut first, rest) = children.split_at_mut(1);
-                let mut best = &mut *first[0];
-                for child in rest {
-                    let new_dist = match &**child {
-                        Node::Branch(bb2, _) => bb2.get_dist(&new.2),
-                        Node::Fruit(bb2, _, _) => bb2.get_dist(&new.2),
-                    };
-                    if new_dist < best_dist {
-                        best_dist = new_dist;
-                        best = &mut **child;
+    /// Set the bounding box of a branch to the union of its children.
+    fn refit(&mut self, index: usize) {
+        if let Node::Branch(_, children) = *self.get_node(index) {
+            let bb = self
+                .get_bounding_box(children[0])
+                .get_union(self.get_bounding_box(children[1]));
+            if let Node::Branch(old_bb, _) = &mut self.nodes[index].node {
+                *old_bb = bb;
+            }
+        }
+    }
+
+    /// Refit and rotate every branch from a node up to the root.
+    fn refit_ancestors(&mut self, mut index: Option<usize>) {
+        while let Some(i) = index {
+            self.refit(i);
+            self.rotate(i);
+            index = self.nodes[i].parent;
+        }
+    }
+
+    /// Add a fruit to the tree.
+    /// It goes next to the node where the half perimeters of the branches grow the least, and the branches are rotated on the way back up.
+    fn insert_leaf(&mut self, proxy: usize, fat_bb: collider::AABB, payload: T) {
+        let leaf = self.allocate(Node::Fruit(fat_bb.clone(), payload), None, proxy);
+        self.proxies[proxy] = Some(leaf);
+        let mut sibling = match self.root {
+            Some(root) => root,
+            None => {
+                self.root = Some(leaf);
+                return;
+            }
+        };
+
+        while let Node::Branch(bb, children) = self.get_node(sibling) {
+            let combined = get_half_perimeter(&bb.get_union(&fat_bb));
+            // cost of a new branch holding this node and the leaf
+            let cost = 2f32 * combined;
+            // every branch above the leaf grows if it goes further down
+            let inheritance = 2f32 * (combined - get_half_perimeter(bb));
+            let child_cost = |child: usize| {
+                let child_bb = self.get_bounding_box(child);
+                let grown = get_half_perimeter(&child_bb.get_union(&fat_bb));
+                match self.get_node(child) {
+                    Node::Branch(_, _) => grown - get_half_perimeter(child_bb) + inheritance,
+                    Node::Fruit(_, _) => grown + inheritance,
+                }
+            };
+            let cost1 = child_cost(children[0]);
+            let cost2 = child_cost(children[1]);
+            if cost < cost1 && cost < cost2 {
+                break;
+            }
+            sibling = if cost1 <= cost2 {
+                children[0]
+            } else {
+                children[1]
+            };
+        }
+
+        let old_parent = self.nodes[sibling].parent;
+        let bb = self.get_bounding_box(sibling).get_union(&fat_bb);
+        let parent = self.allocate(Node::Branch(bb, [sibling, leaf]), old_parent, 0);
+        self.nodes[sibling].parent = Some(parent);
+        self.nodes[leaf].parent = Some(parent);
+        match old_parent {
+            Some(old_parent) => self.replace_child(old_parent, sibling, parent),
+            None => self.root = Some(parent),
+        }
+        self.refit_ancestors(old_parent);
+
+        // reinserted nodes end up wherever there is space, so the order is restored once enough have moved
+        self.inserted += 1;
+        if self.inserted > self.len() {
+            self.optimize_layout();
+        }
+    }
+
+    /// Take a fruit out of the tree and get its payload.
+    /// The sibling of the fruit takes the place of their parent, and the branches above are refit and rotated.
+    fn remove_leaf(&mut self, leaf: usize) -> T {
+        let payload = match std::mem::replace(&mut self.nodes[leaf].node, Node::empty()) {
+            Node::Fruit(_, payload) => payload,
+            Node::Branch(_, _) => unreachable!("proxies always point to fruits"),
+        };
+        self.free.push(leaf);
+        let parent = match self.nodes[leaf].parent {
+            Some(parent) => parent,
+            None => {
+                self.root = None;
+                self.nodes.clear();
+                self.free.clear();
+                return payload;
+            }
+        };
+        let sibling = match self.get_node(parent) {
+            Node::Branch(_, children) if children[0] == leaf => children[1],
+            Node::Branch(_, children) => children[0],
+            Node::Fruit(_, _) => unreachable!("the parent of a node is always a branch"),
+        };
+        let grandparent = self.nodes[parent].parent;
+        self.free.push(parent);
+        self.nodes[sibling].parent = grandparent;
+        match grandparent {
+            Some(grandparent) => {
+                self.replace_child(grandparent, parent, sibling);
+                self.refit_ancestors(Some(grandparent));
+            }
+            None => self.root = Some(sibling),
+        }
+        payload
+    }
+
+    /// Swap a child with a grandchild if it makes the other child smaller, which keeps the tree balanced as it changes.
+    fn rotate(&mut self, index: usize) {
+        let children = match self.get_node(index) {
+            Node::Branch(_, children) => *children,
+            Node::Fruit(_, _) => return,
+        };
+        // (gain, the child that moves down, the grandchild that moves up)
+        let mut best: Option<(f32, usize, usize)> = None;
+        for i in 0..2 {
+            if let Node::Branch(sibling_bb, grandchildren) = self.get_node(children[1 - i]) {
+                for k in 0..2 {
+                    // after the swap the sibling holds child i and the other grandchild
+                    let swapped_bb = self
+                        .get_bounding_box(children[i])
+                        .get_union(self.get_bounding_box(grandchildren[1 - k]));
+                    let gain = get_half_perimeter(sibling_bb) - get_half_perimeter(&swapped_bb);
+                    if gain > best.map_or(0f32, |b| b.0) {
+                        best = Some((gain, children[i], grandchildren[k]));
                     }
                 }
-                best.insert(new);
             }
-            Node::Fruit(bb, _, _) => {
-                let new_branch_bb = new_fruit_bb.get_union(bb);
-                *self = Node::Branch(
-                    new_branch_bb,
-                    [
-                        Box::new(Node::Fruit(new_fruit_bb, new.3, new.0.collision_layers)),
-                        Box::new(self.clone()),
-                    ],
-                );
+        }
+        if let Some((_, child, grandchild)) = best {
+            let sibling = self.nodes[grandchild].parent.unwrap();
+            self.replace_child(index, child, grandchild);
+            self.replace_child(sibling, grandchild, child);
+            self.nodes[grandchild].parent = Some(index);
+            self.nodes[child].parent = Some(sibling);
+            self.refit(sibling);
+        }
+    }
+}
+
+impl<T: Send> Bvh<T> {
+    /// Build a tree top-down from a list of bounding boxes and payloads, choosing how the nodes are split.
+    /// The boxes are grown by the margin, and the proxy of each payload is its index in the list.
+    pub fn build(items: Vec<(collider::AABB, T)>, strategy: BuildStrategy, margin: f32) -> Bvh<T> {
+        let mut bvh = Bvh::with_margin(margin);
+        if items.is_empty() {
+            return bvh;
+        }
+        bvh.proxies = vec![None; items.len()];
+        let data = items
+            .into_iter()
+            .enumerate()
+            .map(|(proxy, (bb, payload))| {
+                (
+                    get_fat_box(&bb, Vector2::new(0f32, 0f32), margin),
+                    proxy,
+                    payload,
+                )
+            })
+            .collect();
+        let mut nodes = Vec::with_capacity(bvh.proxies.len() * 2);
+        bvh.root = Some(build_nodes(data, strategy, &mut nodes));
+        bvh.nodes = nodes;
+        bvh.optimize_layout();
+        bvh
+    }
+}
+
+impl<T: Sync> Bvh<T> {
+    /// Shrink all of the bounding boxes of the branches to the minimal size.
+    /// The top of the tree is split between the threads of the current rayon pool.
+    pub fn shrink(&mut self) {
+        if let Some(root) = self.root {
+            let mut boxes = Vec::new();
+            self.get_shrunk_boxes(root, PARALLEL_SHRINK_DEPTH, &mut boxes);
+            for (index, bb) in boxes {
+                if let Node::Branch(old_bb, _) = &mut self.nodes[index].node {
+                    *old_bb = bb;
+                }
+            }
+        }
+    }
+
+    /// Get the smallest bounding box of everything under a node, collecting the new bounding boxes of the branches.
+    /// The top `depth` levels are split between threads.
+    fn get_shrunk_boxes(
+        &self,
+        index: usize,
+        depth: usize,
+        boxes: &mut Vec<(usize, collider::AABB)>,
+    ) -> collider::AABB {
+        match self.get_node(index) {
+            Node::Branch(_, children) => {
+                let bb = if depth > 0 {
+                    let ((bb1, mut boxes1), (bb2, mut boxes2)) = rayon::join(
+                        || {
+                            let mut boxes = Vec::new();
+                            (
+                                self.get_shrunk_boxes(children[0], depth - 1, &mut boxes),
+                                boxes,
+                            )
+                        },
+                        || {
+                            let mut boxes = Vec::new();
+                            (
+                                self.get_shrunk_boxes(children[1], depth - 1, &mut boxes),
+                                boxes,
+                            )
+                        },
+                    );
+                    boxes.append(&mut boxes1);
+                    boxes.append(&mut boxes2);
+                    bb1.get_union(&bb2)
+                } else {
+                    let bb1 = self.get_shrunk_boxes(children[0], 0, boxes);
+                    bb1.get_union(&self.get_shrunk_boxes(children[1], 0, boxes))
+                };
+                boxes.push((index, bb.clone()));
+                bb
             }
+            Node::Fruit(bb, _) => bb.clone(),
         }
     }
 }
 
+/// Add a node found by a debug query to the result.
+fn push_debug_node<'a, T>(
+    result: &mut (Vec<&'a T>, Vec<(&'a Node<T>, i32)>),
+    node: &'a Node<T>,
+    depth: i32,
+) {
+    result.1.push((node, depth));
+    if let Node::Fruit(_, payload) = node {
+        result.0.push(payload);
+    }
+}
+
 /// This handles broad phase optimization of collisions.
-/// It is a bounding volume hierarchy constructed top-down with 2 subdivisions.
+/// It is a `Bvh` of entity ids and collision layers, with the proxy of every entity stored so they can be moved by id.
 pub struct BVHTree {
-    root_node: Node,
+    tree: Bvh<ColliderLeaf>,
+    /// The proxy of every entity in the tree.
+    proxies: HashMap<u32, usize>,
 }
 
 impl BVHTree {
     /// Create a new BVHTree from the enties.
     pub fn new(data: Vec<EntityData>) -> BVHTree {
+        BVHTree::with_strategy(data, BuildStrategy::Median)
+    }
+
+    /// Create a new BVHTree from the enties, choosing how the nodes are split.
+    pub fn with_strategy(data: Vec<EntityData>, strategy: BuildStrategy) -> BVHTree {
+        let proxies = data
+            .iter()
+            .enumerate()
+            .map(|(proxy, e)| (e.3, proxy))
+            .collect();
+        let items = data
+            .into_iter()
+            .map(|e| (e.2.clone(), (e.3, e.0.collision_layers, e.2)))
+            .collect();
         BVHTree {
-            root_node: Node::new(data),
+            tree: Bvh::build(items, strategy, AABB_MARGIN),
+            proxies,
         }
     }
 
+    /// Get the tree the entities are stored in.
+    pub fn get_tree(&self) -> &Bvh<ColliderLeaf> {
+        &self.tree
+    }
+
+    /// Check if an entity is in the tree.
+    pub fn contains(&self, id: u32) -> bool {
+        self.proxies.contains_key(&id)
+    }
+
+    /// Get the layers an entity was inserted with.
+    pub fn get_layers(&self, id: u32) -> Option<collider::LayerMask> {
+        let proxy = self.proxies.get(&id)?;
+        self.tree.get(*proxy).map(|leaf| leaf.1)
+    }
+
+    /// Get the exact bounding box of an entity, the one it was last inserted or updated with.
+    pub fn get_bounding_box(&self, id: u32) -> Option<collider::AABB> {
+        let proxy = self.proxies.get(&id)?;
+        self.tree.get(*proxy).map(|leaf| leaf.2.clone())
+    }
+
     /// Get all the entity ids in the tree.
     pub fn get_children_id(&self) -> Vec<u32> {
-        self.root_node.get_children_id()
+        self.proxies.keys().copied().collect()
+    }
+
+    /// Get the number of entities in the tree.
+    pub fn len(&self) -> usize {
+        self.proxies.len()
+    }
+
+    /// Check if the tree has no entities.
+    pub fn is_empty(&self) -> bool {
+        self.proxies.is_empty()
     }
 
     /// Get all the entities in the tree.
-    pub fn get_children(&self) -> Vec<&Node> {
-        self.root_node.get_children()
+    pub fn get_children(&self) -> Vec<&Node<ColliderLeaf>> {
+        self.tree.get_nodes()
+    }
+
+    /// Get all the entity ids in the tree whose bounding box contains a point.
+    pub fn query_point(&self, p: &Vector2, layers: &collider::LayerMask) -> Vec<u32> {
+        get_ids(
+            self.tree
+                .query_point(p, exact_filter(layers, |bb| collide_point(bb, p))),
+        )
     }
 
-    /// Get all the entity ids in the tree that collide with a point.
-    pub fn query_point(&self, p: &Vector2, layers: &[bool; collider::LAYERS]) -> Vec<u32> {
-        self.root_node.query_point(p, layers).unwrap_or_default()
+    /// Get all the entity ids in the tree whose bounding box collides with a bounding box.
+    pub fn query_rect(&self, r: &collider::AABB, layers: &collider::LayerMask) -> Vec<u32> {
+        get_ids(
+            self.tree
+                .query_rect(r, exact_filter(layers, |bb| bb.is_colliding(r))),
+        )
     }
 
-    /// Get all the entity ids in the tree that collide with a bounding box.
-    pub fn query_rect(&self, r: &collider::AABB, layers: &[bool; collider::LAYERS]) -> Vec<u32> {
-        self.root_node.query_rect(r, layers).unwrap_or_default()
+    /// Get all the entity ids in the tree whose bounding box is within a radius of a point.
+    pub fn query_circle(
+        &self,
+        center: &Vector2,
+        radius: f32,
+        layers: &collider::LayerMask,
+    ) -> Vec<u32> {
+        let circle = (*center, radius);
+        get_ids(self.tree.query_circle(
+            center,
+            radius,
+            exact_filter(layers, |bb| collide_circle(bb, &circle)),
+        ))
+    }
+
+    /// Get the ids of the k entities in the tree closest to a point, closest first.
+    /// The distance is measured to the closest point of the bounding boxes.
+    pub fn nearest(&self, p: &Vector2, k: usize, layers: &collider::LayerMask) -> Vec<u32> {
+        get_ids(
+            self.tree
+                .nearest_by(p, k, layer_filter(layers), |leaf| get_dist_sqr(&leaf.2, p)),
+        )
+    }
+
+    /// Get every pair of entities whose bounding boxes collide, each pair once with the smaller id first.
+    /// The filter gets the layers of both entities and decides if the pair is kept.
+    pub fn overlapping_pairs(
+        &self,
+        filter: impl Fn(&collider::LayerMask, &collider::LayerMask) -> bool,
+    ) -> Vec<(u32, u32)> {
+        self.tree
+            .overlapping_pairs(|a, b| a.2.is_colliding(&b.2) && filter(&a.1, &b.1))
+            .into_iter()
+            .map(|(a, b)| if a.0 < b.0 { (a.0, b.0) } else { (b.0, a.0) })
+            .collect()
     }
 
     /// Get all the entity ids along with the nodes that contian them in the tree that collide with a bounding box.
     pub fn debug_query_rect(
         &self,
         r: &collider::AABB,
-        layers: &[bool; collider::LAYERS],
-    ) -> (Option<Vec<u32>>, Vec<(&Node, i32)>) {
-        self.root_node.debug_query_rect(r, layers)
+        layers: &collider::LayerMask,
+    ) -> (Option<Vec<u32>>, Vec<(&Node<ColliderLeaf>, i32)>) {
+        let (found, nodes) = self
+            .tree
+            .debug_query_rect(r, exact_filter(layers, |bb| bb.is_colliding(r)));
+        (get_debug_ids(found), nodes)
     }
 
     /// Get all the entity ids along with the nodes that contian them in the tree that collide with a point.
     pub fn debug_query_point(
         &self,
         p: &Vector2,
-        layers: &[bool; collider::LAYERS],
-    ) -> (Option<Vec<u32>>, Vec<(&Node, i32)>) {
-        self.root_node.debug_query_point(p, layers)
+        layers: &collider::LayerMask,
+    ) -> (Option<Vec<u32>>, Vec<(&Node<ColliderLeaf>, i32)>) {
+        let (found, nodes) = self
+            .tree
+            .debug_query_point(p, exact_filter(layers, |bb| collide_point(bb, p)));
+        (get_debug_ids(found), nodes)
+    }
+
+    /// Get the closest entity that a ray hits, checking the exact shape of the colliders.
+    pub fn raycast(
+        &self,
+        origin: Vector2,
+        direction: Vector2,
+        max_distance: f32,
+        layers: &collider::LayerMask,
+        data: &BvhData,
+    ) -> Option<RayHit> {
+        let mut closest = None;
+        self.raycast_with(origin, direction, max_distance, layers, data, |hit| {
+            closest = Some(hit);
+            hit.distance
+        });
+        closest
+    }
+
+    /// Get all of the entities that a ray hits, sorted by distance.
+    pub fn raycast_all(
+        &self,
+        origin: Vector2,
+        direction: Vector2,
+        max_distance: f32,
+        layers: &collider::LayerMask,
+        data: &BvhData,
+    ) -> Vec<RayHit> {
+        let mut hits = Vec::new();
+        self.raycast_with(origin, direction, max_distance, layers, data, |hit| {
+            hits.push(hit);
+            max_distance
+        });
+        hits.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap());
+        hits
+    }
+
+    /// Move a shape from one position to another and get the first entity it touches.
+    /// Entities that the shape already overlaps at the start are hit at time zero, so leave the moving entity out of the layers.
+    pub fn shape_cast(
+        &self,
+        shape: &collider::Shape,
+        from: Vector2,
+        to: Vector2,
+        layers: &collider::LayerMask,
+        (entities, positions, colliders, rotations): &BvhData,
+    ) -> Option<ShapeHit> {
+        let swept_bb = shape
+            .get_bounding_box(&from)
+            .get_union(&shape.get_bounding_box(&to));
+        let mut first: Option<ShapeHit> = None;
+        for id in self.query_rect(&swept_bb, layers) {
+            let entity = entities.entity(id);
+            let (pos, col) = match (positions.get(entity), colliders.get(entity)) {
+                (Some(pos), Some(col)) if entities.is_alive(entity) => (pos, col),
+                _ => continue,
+            };
+            let other = col
+                .shape
+                .rotated(rotations.get(entity).map_or(0f32, |r| r.0));
+            if let Some((time, normal)) = shape.get_time_of_impact(&from, &to, &pos.0, &other) {
+                if first.map_or(true, |hit| time < hit.time) {
+                    first = Some(ShapeHit {
+                        id,
+                        time,
+                        position: from + (to - from) * time,
+                        normal,
+                    });
+                }
+            }
+        }
+        first
+    }
+
+    /// Run the callback for entities hit by a ray, it returns the new max distance to search.
+    fn raycast_with(
+        &self,
+        origin: Vector2,
+        direction: Vector2,
+        max_distance: f32,
+        layers: &collider::LayerMask,
+        (entities, positions, colliders, rotations): &BvhData,
+        mut callback: impl FnMut(RayHit) -> f32,
+    ) {
+        if direction.length_sqr() == 0f32 {
+            return;
+        }
+        let direction = direction.normalized();
+        self.tree.raycast(
+            &origin,
+            &direction,
+            max_distance,
+            layer_filter(layers),
+            |(id, _, _), max_distance| {
+                let entity = entities.entity(*id);
+                // the tree can still contain entities that were deleted since it was built
+                let hit = match (positions.get(entity), colliders.get(entity)) {
+                    (Some(pos), Some(col)) if entities.is_alive(entity) => col.raycast(
+                        &pos.0,
+                        rotations.get(entity).map_or(0f32, |r| r.0),
+                        &origin,
+                        &direction,
+                        max_distance,
+                    ),
+                    _ => None,
+                };
+                match hit {
+                    Some((distance, normal)) => callback(RayHit {
+                        id: *id,
+                        point: origin + direction * distance,
+                        normal,
+                        distance,
+                    }),
+                    None => max_distance,
+                }
+            },
+        );
     }
 
-    /// Update a bounding box within the tree.
-    pub fn update(&mut self, old: (&collider::AABB, u32), new: (&collider::AABB, u32)) {
-        self.root_node.update(old, new);
+    /// Move an entity in the tree to a new bounding box, returns false if the entity is not in the tree.
+    /// See `Bvh::update` for how the displacement is used.
+    pub fn update(&mut self, id: u32, bb: &collider::AABB, displacement: Vector2) -> bool {
+        let proxy = match self.proxies.get(&id) {
+            Some(proxy) => *proxy,
+            None => return false,
+        };
+        // the fat box may not move, but queries use the exact box
+        if let Some(leaf) = self.tree.get_mut(proxy) {
+            leaf.2 = bb.clone();
+        }
+        self.tree.update(proxy, bb, displacement)
     }
 
-    /// Create a new entity in the tree.
+    /// Create a new entity in the tree, replacing it if it is already in the tree.
     pub fn insert(&mut self, new: &(&collider::Collider, Vector2, collider::AABB, u32)) {
-        self.root_node.insert(new);
+        self.delete(new.3);
+        let proxy = self
+            .tree
+            .insert(&new.2, (new.3, new.0.collision_layers, new.2.clone()));
+        self.proxies.insert(new.3, proxy);
     }
 
-    /// Remove a entity from the tree.
-    pub fn delete(&mut self, old: u32) {
-        self.root_node.delete(old);
+    /// Remove a entity from the tree, returns false if it was not in the tree.
+    pub fn delete(&mut self, old: u32) -> bool {
+        match self.proxies.remove(&old) {
+            Some(proxy) => self.tree.remove(proxy).is_some(),
+            None => false,
+        }
+    }
+
+    /// Put the nodes in depth first order, see `Bvh::optimize_layout`.
+    pub fn optimize_layout(&mut self) {
+        self.tree.optimize_layout();
     }
 
     /// Shrink all of the bounding boxes in the tree to the minimal size.
+    /// The top of the tree is split between the threads of the current rayon pool.
     pub fn shrink(&mut self) {
-        self.root_node.shrink();
+        self.tree.shrink();
+    }
+}
+
+/// Get a filter that keeps the entities on any of the layers.
+fn layer_filter(layers: &collider::LayerMask) -> impl Fn(&ColliderLeaf) -> bool + '_ {
+    move |leaf| leaf.1.intersects(layers)
+}
+
+/// Get a filter that keeps the entities on any of the layers whose exact bounding box passes the test.
+fn exact_filter<'a>(
+    layers: &'a collider::LayerMask,
+    test: impl Fn(&collider::AABB) -> bool + 'a,
+) -> impl Fn(&ColliderLeaf) -> bool + 'a {
+    move |leaf| leaf.1.intersects(layers) && test(&leaf.2)
+}
+
+/// Get the entity ids out of a query result.
+fn get_ids(found: Vec<&ColliderLeaf>) -> Vec<u32> {
+    found.into_iter().map(|leaf| leaf.0).collect()
+}
+
+/// Get the entity ids out of a debug query result, None if nothing was found.
+fn get_debug_ids(found: Vec<&ColliderLeaf>) -> Option<Vec<u32>> {
+    if found.is_empty() {
+        None
+    } else {
+        Some(get_ids(found))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aabb(lx: f32, ly: f32, rx: f32, ry: f32) -> collider::AABB {
+        collider::AABB { lx, ly, rx, ry }
+    }
+
+    #[test]
+    fn nearest_measures_to_the_closest_point_of_the_box() {
+        let mut bvh = Bvh::new();
+        // the large box is farther away, but its center is level with the point
+        bvh.insert(&aabb(10f32, -500f32, 1000f32, 500f32), "large");
+        bvh.insert(&aabb(7f32, 0f32, 8f32, 1f32), "small");
+        let p = Vector2::new(0f32, 0f32);
+        assert_eq!(bvh.nearest(&p, 2, |_| true), vec![&"small", &"large"]);
+    }
+
+    /// Check that every pair of fruits whose boxes collide is found exactly once.
+    fn assert_pairs_found_once(bvh: &Bvh<u32>) {
+        let fruits: Vec<_> = bvh
+            .get_nodes()
+            .into_iter()
+            .filter_map(|node| match node {
+                Node::Fruit(bb, id) => Some((bb, *id)),
+                Node::Branch(_, _) => None,
+            })
+            .collect();
+        let mut expected = Vec::new();
+        for (i, (bb_a, a)) in fruits.iter().enumerate() {
+            for (bb_b, b) in &fruits[i + 1..] {
+                if bb_a.is_colliding(bb_b) {
+                    expected.push((*a.min(b), *a.max(b)));
+                }
+            }
+        }
+        expected.sort_unstable();
+        let mut pairs: Vec<_> = bvh
+            .overlapping_pairs(|_, _| true)
+            .into_iter()
+            .map(|(a, b)| (*a.min(b), *a.max(b)))
+            .collect();
+        pairs.sort_unstable();
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn overlapping_pairs_are_found_once() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(25);
+        // boxes snapped to a grid share their edges, so many of them lie on the split of a branch
+        let random_box = |rng: &mut StdRng| {
+            let lx = rng.gen_range(0..40) as f32 * 5f32;
+            let ly = rng.gen_range(0..40) as f32 * 5f32;
+            let w = rng.gen_range(1..5) as f32 * 5f32;
+            let h = rng.gen_range(1..5) as f32 * 5f32;
+            aabb(lx, ly, lx + w, ly + h)
+        };
+        for &strategy in &[BuildStrategy::Median, BuildStrategy::Sah] {
+            for &margin in &[0f32, AABB_MARGIN] {
+                let items = (0..300u32).map(|id| (random_box(&mut rng), id)).collect();
+                let mut bvh = Bvh::build(items, strategy, margin);
+                assert_pairs_found_once(&bvh);
+
+                // moved, removed and inserted fruits go through the incremental path
+                for proxy in 0..300 {
+                    match rng.gen_range(0..3) {
+                        0 => {
+                            bvh.update(proxy, &random_box(&mut rng), Vector2::new(5f32, 0f32));
+                        }
+                        1 => {
+                            bvh.remove(proxy);
+                        }
+                        _ => (),
+                    }
+                }
+                for id in 300..400 {
+                    bvh.insert(&random_box(&mut rng), id);
+                }
+                assert_pairs_found_once(&bvh);
+                bvh.optimize_layout();
+                assert_pairs_found_once(&bvh);
+            }
+        }
+    }
+
+    #[test]
+    fn queries_use_the_exact_box_of_moving_leaves() {
+        let col = collider::Collider {
+            shape: collider::Shape::RectangeCollider {
+                size: Vector2::new(10f32, 10f32),
+            },
+            physics_collider: true,
+            collision_layers: collider::LayerMask::layer(0),
+            collision_mask: collider::LayerMask::layer(0),
+        };
+        let layers = collider::LayerMask::ALL;
+        let mut tree = BVHTree::new(vec![
+            (
+                &col,
+                Vector2::new(0f32, 0f32),
+                aabb(0f32, 0f32, 10f32, 10f32),
+                0,
+            ),
+            (
+                &col,
+                Vector2::new(66f32, 0f32),
+                aabb(66f32, 0f32, 67f32, 10f32),
+                1,
+            ),
+        ]);
+
+        // a small move stays inside the fat box, which keeps covering where the leaf was
+        tree.update(0, &aabb(3f32, 0f32, 13f32, 10f32), Vector2::new(3f32, 0f32));
+        assert_eq!(tree.query_point(&Vector2::new(1f32, 5f32), &layers), vec![]);
+        assert_eq!(
+            tree.query_point(&Vector2::new(12f32, 5f32), &layers),
+            vec![0]
+        );
+
+        // a big move stretches the fat box far ahead of the leaf
+        tree.update(
+            0,
+            &aabb(40f32, 0f32, 50f32, 10f32),
+            Vector2::new(30f32, 0f32),
+        );
+        let fat_right = 50f32 + AABB_MARGIN + 30f32 * DISPLACEMENT_MULTIPLIER;
+        assert!(tree.get_children().iter().any(|node| match node {
+            Node::Fruit(bb, leaf) => leaf.0 == 0 && bb.rx == fat_right,
+            _ => false,
+        }));
+
+        let p = Vector2::new(60f32, 5f32);
+        assert_eq!(tree.query_point(&p, &layers), vec![]);
+        assert_eq!(
+            tree.query_rect(&aabb(55f32, 0f32, 58f32, 10f32), &layers),
+            vec![]
+        );
+        assert_eq!(tree.query_circle(&p, 8f32, &layers), vec![1]);
+        assert_eq!(tree.nearest(&p, 2, &layers), vec![1, 0]);
+        assert!(tree.overlapping_pairs(|_, _| true).is_empty());
     }
 }
@@ -1,5 +1,8 @@
+use crate::utils;
 use raylib::math::Vector2;
 use specs::{Component, VecStorage};
+use std::borrow::Cow;
+use std::ops::{BitAnd, BitOr, Not};
 
 pub const LAYERS: usize = 128;
 
@@ -10,8 +13,10 @@ pub const LAYERS: usize = 128;
 pub struct Collider {
     pub shape: Shape,
     pub physics_collider: bool,
-    pub collision_layers: [bool; LAYERS],
-    pub collision_mask: [bool; LAYERS],
+    /// The layers this collider is on.
+    pub collision_layers: LayerMask,
+    /// The layers this collider detects.
+    pub collision_mask: LayerMask,
 }
 
 impl Collider {
@@ -34,16 +39,92 @@ impl Collider {
     pub fn get_bounding_box(&self, pos: &Vector2) -> AABB {
         self.shape.get_bounding_box(pos)
     }
+
+    /// Returns the collision vector between two rotated colliders if there is a collision, otherwise None.
+    pub fn get_collision_rotated(
+        &self,
+        pos: &Vector2,
+        rotation: f32,
+        other_pos: &Vector2,
+        other_rotation: f32,
+        other: &Collider,
+    ) -> Option<Vector2> {
+        self.shape.rotated(rotation).get_collision(
+            pos,
+            other_pos,
+            &other.shape.rotated(other_rotation),
+        )
+    }
+
+    /// Returns the collision vector of a rotated collider if there is a collision within the bounds (walls), otherwise None.
+    pub fn get_collision_bounds_rotated(
+        &self,
+        pos: &Vector2,
+        rotation: f32,
+        bounds: [f32; 4],
+    ) -> Option<Vector2> {
+        self.shape
+            .rotated(rotation)
+            .get_collision_bounds(pos, bounds)
+    }
+
+    /// Returns the bounding box of the rotated collider.
+    pub fn get_bounding_box_rotated(&self, pos: &Vector2, rotation: f32) -> AABB {
+        self.shape.rotated(rotation).get_bounding_box(pos)
+    }
+
+    /// Returns the distance along a ray and the surface normal where the ray hits the rotated collider, otherwise None.
+    /// The direction must be normalized.
+    pub fn raycast(
+        &self,
+        pos: &Vector2,
+        rotation: f32,
+        origin: &Vector2,
+        direction: &Vector2,
+        max_distance: f32,
+    ) -> Option<(f32, Vector2)> {
+        self.shape
+            .rotated(rotation)
+            .raycast(pos, origin, direction, max_distance)
+    }
+
+    /// Returns the moment of inertia of the collider's shape around its center for a given mass.
+    pub fn get_inertia(&self, mass: f32) -> f32 {
+        self.shape.get_inertia(mass)
+    }
+
+    /// Returns the point where the collider touches another one, from the collision vector between them.
+    pub fn get_contact_point(
+        &self,
+        pos: &Vector2,
+        other_pos: &Vector2,
+        other: &Collider,
+        collision_vec: Vector2,
+    ) -> Vector2 {
+        self.shape
+            .get_contact_point(pos, other_pos, &other.shape, collision_vec)
+    }
 }
 
 /// A shape that can be used for collisions.
+/// Polygons must be convex and have at least three vertices, which are relative to the position of the entity.
 #[derive(Debug, Clone)]
 pub enum Shape {
     CircleCollider { radius: f32 },
     RectangeCollider { size: Vector2 },
+    PolygonCollider { vertices: Vec<Vector2> },
 }
 
 impl Shape {
+    /// Create a polygon shape, panics if it has less than three vertices.
+    pub fn polygon(vertices: Vec<Vector2>) -> Shape {
+        assert!(
+            vertices.len() >= 3,
+            "polygons must have at least three vertices"
+        );
+        Shape::PolygonCollider { vertices }
+    }
+
     fn get_collision(&self, pos: &Vector2, other_pos: &Vector2, other: &Shape) -> Option<Vector2> {
         match self {
             Shape::CircleCollider { radius } => match other {
@@ -63,11 +144,45 @@ impl Shape {
                         return Some(collision_vec * (sum_r - dist));
                     }
                 }
-                Shape::RectangeCollider { size: _ } => todo!(),
+                Shape::RectangeCollider { size } => {
+                    return get_circle_rect_collision(pos, *radius, other_pos, size);
+                }
+                Shape::PolygonCollider { vertices: _ } => {
+                    let center = *pos + Vector2::one() * (*radius);
+                    return get_circle_polygon_collision(
+                        &center,
+                        *radius,
+                        &other.get_vertices(other_pos),
+                    );
+                }
+            },
+            Shape::RectangeCollider { size } => match other {
+                Shape::CircleCollider { radius } => {
+                    // the same collision from the other side, so the vector is flipped
+                    return get_circle_rect_collision(other_pos, *radius, pos, size).map(|v| -v);
+                }
+                Shape::RectangeCollider { size: other_size } => {
+                    return get_rect_collision(pos, size, other_pos, other_size);
+                }
+                Shape::PolygonCollider { vertices: _ } => {
+                    return get_polygon_collision(
+                        &self.get_vertices(pos),
+                        &other.get_vertices(other_pos),
+                    );
+                }
             },
-            Shape::RectangeCollider { size: _ } => match other {
-                Shape::CircleCollider { radius: _ } => todo!(),
-                Shape::RectangeCollider { size: _ } => todo!(),
+            Shape::PolygonCollider { vertices: _ } => match other {
+                Shape::CircleCollider { radius } => {
+                    let center = *other_pos + Vector2::one() * (*radius);
+                    return get_circle_polygon_collision(&center, *radius, &self.get_vertices(pos))
+                        .map(|v| -v);
+                }
+                Shape::RectangeCollider { size: _ } | Shape::PolygonCollider { vertices: _ } => {
+                    return get_polygon_collision(
+                        &self.get_vertices(pos),
+                        &other.get_vertices(other_pos),
+                    );
+                }
             },
         }
         None
@@ -90,7 +205,7 @@ impl Shape {
         None
     }
 
-    fn get_bounding_box(&self, pos: &Vector2) -> AABB {
+    pub fn get_bounding_box(&self, pos: &Vector2) -> AABB {
         match self {
             Shape::CircleCollider { radius } => AABB {
                 lx: pos.x,
@@ -102,10 +217,597 @@ impl Shape {
                 lx: pos.x,
                 rx: pos.x + size.x,
                 ly: pos.y,
-                ry: pos.y + size.x,
+                ry: pos.y + size.y,
             },
+            Shape::PolygonCollider { vertices } => {
+                let first = *pos
+                    + *vertices
+                        .first()
+                        .expect("polygons must have at least three vertices");
+                let mut bb = AABB {
+                    lx: first.x,
+                    rx: first.x,
+                    ly: first.y,
+                    ry: first.y,
+                };
+                for v in &vertices[1..] {
+                    bb = bb.with_point(&(*pos + *v));
+                }
+                bb
+            }
+        }
+    }
+
+    /// Get the shape rotated around the center of its bounding box.
+    /// Rotated rectangles become polygons, and circles are not changed.
+    pub fn rotated(&self, rotation: f32) -> Cow<'_, Shape> {
+        if rotation == 0f32 {
+            return Cow::Borrowed(self);
+        }
+        match self {
+            Shape::CircleCollider { radius: _ } => Cow::Borrowed(self),
+            Shape::RectangeCollider { size: _ } | Shape::PolygonCollider { vertices: _ } => {
+                let vertices = self.get_vertices(&Vector2::new(0f32, 0f32));
+                let center = self.get_center();
+                Cow::Owned(Shape::PolygonCollider {
+                    vertices: vertices
+                        .iter()
+                        .map(|v| center + utils::rotate(*v - center, rotation))
+                        .collect(),
+                })
+            }
+        }
+    }
+
+    /// Returns the point where the shape touches another shape, from the collision vector pointing from this shape to the other.
+    /// Circles touch halfway into the overlap along the collision vector, polygons at the middle of the area they share.
+    pub fn get_contact_point(
+        &self,
+        pos: &Vector2,
+        other_pos: &Vector2,
+        other: &Shape,
+        collision_vec: Vector2,
+    ) -> Vector2 {
+        let depth = collision_vec.length();
+        let normal = if depth > 0f32 {
+            collision_vec / depth
+        } else {
+            Vector2::new(0f32, 0f32)
+        };
+        match (self, other) {
+            (Shape::CircleCollider { radius }, _) => {
+                *pos + Vector2::one() * *radius + normal * (*radius - depth / 2f32)
+            }
+            (_, Shape::CircleCollider { radius }) => {
+                *other_pos + Vector2::one() * *radius - normal * (*radius - depth / 2f32)
+            }
+            _ => {
+                let vertices = self.get_vertices(pos);
+                let other_vertices = other.get_vertices(other_pos);
+                get_polygon_contact_point(&vertices, &other_vertices).unwrap_or_else(|| {
+                    (get_centroid(&vertices) + get_centroid(&other_vertices)) / 2f32
+                })
+            }
+        }
+    }
+
+    /// Returns when the shape moving from one position to another first touches another shape, otherwise None.
+    /// The time is the fraction of the path the shape can move without overlapping, and the normal points from the other shape towards this one.
+    pub fn get_time_of_impact(
+        &self,
+        from: &Vector2,
+        to: &Vector2,
+        other_pos: &Vector2,
+        other: &Shape,
+    ) -> Option<(f32, Vector2)> {
+        let path = *to - *from;
+        if let Some(collision_vec) = self.get_collision(from, other_pos, other) {
+            let normal = if collision_vec.length_sqr() > 0f32 {
+                -collision_vec.normalized()
+            } else if path.length_sqr() > 0f32 {
+                -path.normalized()
+            } else {
+                Vector2::new(0f32, 0f32)
+            };
+            return Some((0f32, normal));
+        }
+        if path.length_sqr() == 0f32 {
+            return None;
+        }
+        match (self, other) {
+            (
+                Shape::CircleCollider { radius },
+                Shape::CircleCollider {
+                    radius: other_radius,
+                },
+            ) => {
+                let length = path.length();
+                get_circle_raycast(
+                    *other_pos + Vector2::one() * *other_radius,
+                    radius + other_radius,
+                    &(*from + Vector2::one() * *radius),
+                    &(path / length),
+                )
+                .filter(|(distance, _)| *distance <= length)
+                .map(|(distance, normal)| (distance / length, normal))
+            }
+            (Shape::CircleCollider { radius }, _) => get_circle_polygon_time_of_impact(
+                &(*from + Vector2::one() * *radius),
+                *radius,
+                &path,
+                &other.get_vertices(other_pos),
+            ),
+            // the same as the circle moving the other way past this shape
+            (_, Shape::CircleCollider { radius }) => get_circle_polygon_time_of_impact(
+                &(*other_pos + Vector2::one() * *radius),
+                *radius,
+                &-path,
+                &self.get_vertices(from),
+            )
+            .map(|(time, normal)| (time, -normal)),
+            _ => get_polygon_time_of_impact(
+                &self.get_vertices(from),
+                &path,
+                &other.get_vertices(other_pos),
+            ),
+        }
+    }
+
+    /// Returns the distance along a ray and the surface normal where the ray hits the shape, otherwise None.
+    /// A ray that starts inside the shape hits it at distance zero.
+    fn raycast(
+        &self,
+        pos: &Vector2,
+        origin: &Vector2,
+        direction: &Vector2,
+        max_distance: f32,
+    ) -> Option<(f32, Vector2)> {
+        match self {
+            Shape::CircleCollider { radius } => {
+                get_circle_raycast(*pos + Vector2::one() * *radius, *radius, origin, direction)
+            }
+            Shape::RectangeCollider { size: _ } | Shape::PolygonCollider { vertices: _ } => {
+                get_polygon_raycast(&self.get_vertices(pos), origin, direction, max_distance)
+            }
+        }
+        .filter(|(distance, _)| *distance <= max_distance)
+    }
+
+    /// Get the center of the bounding box relative to the position of the entity, shapes rotate around this point.
+    pub fn get_center(&self) -> Vector2 {
+        let bb = self.get_bounding_box(&Vector2::new(0f32, 0f32));
+        Vector2::new((bb.lx + bb.rx) / 2f32, (bb.ly + bb.ry) / 2f32)
+    }
+
+    /// Get the moment of inertia around the center of the shape for a given mass.
+    fn get_inertia(&self, mass: f32) -> f32 {
+        match self {
+            Shape::CircleCollider { radius } => mass * radius * radius / 2f32,
+            Shape::RectangeCollider { size } => mass * (size.x * size.x + size.y * size.y) / 12f32,
+            Shape::PolygonCollider { vertices } => {
+                let center = self.get_center();
+                let mut numerator = 0f32;
+                let mut denominator = 0f32;
+                for i in 0..vertices.len() {
+                    let p1 = vertices[i] - center;
+                    let p2 = vertices[(i + 1) % vertices.len()] - center;
+                    let cross = (p1.x * p2.y - p1.y * p2.x).abs();
+                    numerator += cross * (p1.dot(p1) + p1.dot(p2) + p2.dot(p2));
+                    denominator += cross;
+                }
+                if denominator == 0f32 {
+                    return 0f32;
+                }
+                mass * numerator / (6f32 * denominator)
+            }
+        }
+    }
+
+    /// Get the corners of a rectangle or polygon in world space. Circles have no corners.
+    fn get_vertices(&self, pos: &Vector2) -> Vec<Vector2> {
+        match self {
+            Shape::CircleCollider { radius: _ } => Vec::new(),
+            Shape::RectangeCollider { size } => vec![
+                *pos,
+                *pos + Vector2::new(size.x, 0f32),
+                *pos + *size,
+                *pos + Vector2::new(0f32, size.y),
+            ],
+            Shape::PolygonCollider { vertices } => vertices.iter().map(|v| *pos + *v).collect(),
+        }
+    }
+}
+
+/// Get the average of the vertices of a polygon.
+fn get_centroid(vertices: &[Vector2]) -> Vector2 {
+    let mut sum = Vector2::new(0f32, 0f32);
+    for v in vertices {
+        sum += *v;
+    }
+    sum / vertices.len() as f32
+}
+
+/// Project the vertices of a polygon onto an axis, returning the minimum and maximum.
+fn project(vertices: &[Vector2], axis: Vector2) -> (f32, f32) {
+    let mut min = f32::INFINITY;
+    let mut max = f32::NEG_INFINITY;
+    for v in vertices {
+        let p = v.dot(axis);
+        min = min.min(p);
+        max = max.max(p);
+    }
+    (min, max)
+}
+
+/// Get the normalized normals of every edge of a polygon.
+fn get_edge_normals(vertices: &[Vector2]) -> impl Iterator<Item = Vector2> + '_ {
+    (0..vertices.len()).filter_map(move |i| {
+        let edge = vertices[(i + 1) % vertices.len()] - vertices[i];
+        let normal = Vector2::new(-edge.y, edge.x);
+        if normal.length_sqr() > 0f32 {
+            Some(normal.normalized())
+        } else {
+            None
+        }
+    })
+}
+
+/// Get where a ray hits a circle, as the distance along the ray and the normal of the surface.
+fn get_circle_raycast(
+    center: Vector2,
+    radius: f32,
+    origin: &Vector2,
+    direction: &Vector2,
+) -> Option<(f32, Vector2)> {
+    let offset = *origin - center;
+    let b = offset.dot(*direction);
+    let c = offset.length_sqr() - radius * radius;
+    // the ray starts inside the circle
+    if c <= 0f32 {
+        return Some((0f32, -*direction));
+    }
+    // the ray starts outside and points away
+    if b > 0f32 {
+        return None;
+    }
+    let discriminant = b * b - c;
+    if discriminant < 0f32 {
+        return None;
+    }
+    let distance = -b - discriminant.sqrt();
+    let normal = (*origin + *direction * distance - center) / radius;
+    Some((distance, normal))
+}
+
+/// Get where a ray hits a convex polygon, as the distance along the ray and the normal of the edge that was hit.
+fn get_polygon_raycast(
+    vertices: &[Vector2],
+    origin: &Vector2,
+    direction: &Vector2,
+    max_distance: f32,
+) -> Option<(f32, Vector2)> {
+    let centroid = get_centroid(vertices);
+    let mut enter = 0f32;
+    let mut exit = max_distance;
+    let mut enter_normal = None;
+    // clip the ray against the half plane behind every edge
+    for (start, end) in vertices.iter().zip(vertices.iter().cycle().skip(1)) {
+        let edge = *end - *start;
+        let mut normal = Vector2::new(-edge.y, edge.x);
+        // edges without a length don't have a normal
+        if normal.length_sqr() == 0f32 {
+            continue;
+        }
+        normal.normalize();
+        let point = *start;
+        // the winding of the polygon is not known, so make the normal point outwards
+        if normal.dot(point - centroid) < 0f32 {
+            normal = -normal;
+        }
+        let distance_to_edge = normal.dot(point - *origin);
+        let speed = normal.dot(*direction);
+        if speed == 0f32 {
+            // parallel to the edge and outside of it
+            if distance_to_edge < 0f32 {
+                return None;
+            }
+        } else {
+            let t = distance_to_edge / speed;
+            if speed < 0f32 {
+                if t > enter {
+                    enter = t;
+                    enter_normal = Some(normal);
+                }
+            } else {
+                exit = exit.min(t);
+            }
+            if enter > exit {
+                return None;
+            }
+        }
+    }
+    Some((enter, enter_normal.unwrap_or(-*direction)))
+}
+
+/// Get when a convex polygon moving along a path first touches another one,
+/// as the fraction of the path and the normal pointing from the other polygon towards the moving one.
+fn get_polygon_time_of_impact(
+    vertices: &[Vector2],
+    path: &Vector2,
+    other_vertices: &[Vector2],
+) -> Option<(f32, Vector2)> {
+    // the polygons overlap while their projections overlap on every axis,
+    // so the impact is when the last axis starts overlapping
+    let mut enter = (f32::NEG_INFINITY, -path.normalized());
+    let mut exit = f32::INFINITY;
+    for axis in get_edge_normals(vertices).chain(get_edge_normals(other_vertices)) {
+        let (min, max) = project(vertices, axis);
+        let (other_min, other_max) = project(other_vertices, axis);
+        let speed = axis.dot(*path);
+        if speed == 0f32 {
+            // never overlapping on this axis
+            if min >= other_max || max <= other_min {
+                return None;
+            }
+            continue;
+        }
+        let (start, end, normal) = if speed > 0f32 {
+            ((other_min - max) / speed, (other_max - min) / speed, -axis)
+        } else {
+            ((other_max - min) / speed, (other_min - max) / speed, axis)
+        };
+        if start > enter.0 {
+            enter = (start, normal);
+        }
+        exit = exit.min(end);
+        if enter.0 >= exit {
+            return None;
+        }
+    }
+    if enter.0 > 1f32 || exit <= 0f32 {
+        return None;
+    }
+    Some((enter.0.max(0f32), enter.1))
+}
+
+/// Get when a circle moving along a path first touches a convex polygon,
+/// as the fraction of the path and the normal pointing from the polygon towards the circle.
+fn get_circle_polygon_time_of_impact(
+    center: &Vector2,
+    radius: f32,
+    path: &Vector2,
+    vertices: &[Vector2],
+) -> Option<(f32, Vector2)> {
+    let length = path.length();
+    let direction = *path / length;
+    let centroid = get_centroid(vertices);
+    // the center of the circle touches the polygon grown by the radius,
+    // which is the edges moved out by the radius and a circle around every vertex
+    let mut hit = (f32::INFINITY, Vector2::new(0f32, 0f32));
+    let mut add_hit = |distance: f32, normal: Vector2| {
+        if distance < hit.0 {
+            hit = (distance, normal);
+        }
+    };
+    for (start, end) in vertices.iter().zip(vertices.iter().cycle().skip(1)) {
+        if let Some((distance, normal)) = get_circle_raycast(*start, radius, center, &direction) {
+            add_hit(distance, normal);
+        }
+        let edge = *end - *start;
+        let mut normal = Vector2::new(-edge.y, edge.x);
+        if normal.length_sqr() == 0f32 {
+            continue;
+        }
+        normal.normalize();
+        if normal.dot(*start - centroid) < 0f32 {
+            normal = -normal;
+        }
+        let speed = normal.dot(direction);
+        // only edges facing the circle's path can be hit
+        if speed >= 0f32 {
+            continue;
+        }
+        let distance = normal.dot(*start + normal * radius - *center) / speed;
+        let along = (*center + direction * distance - *start).dot(edge) / edge.length_sqr();
+        if distance >= 0f32 && (0f32..=1f32).contains(&along) {
+            add_hit(distance, normal);
+        }
+    }
+    if hit.0 > length {
+        return None;
+    }
+    Some((hit.0 / length, hit.1))
+}
+
+/// Returns the shortest vector that separates two convex polygons using the separating axis theorem, pointing from the first polygon to the second.
+fn get_polygon_collision(vertices: &[Vector2], other_vertices: &[Vector2]) -> Option<Vector2> {
+    let mut best_overlap = f32::INFINITY;
+    let mut best_axis = Vector2::new(0f32, 0f32);
+    for axis in get_edge_normals(vertices).chain(get_edge_normals(other_vertices)) {
+        let (min, max) = project(vertices, axis);
+        let (other_min, other_max) = project(other_vertices, axis);
+        let overlap = max.min(other_max) - min.max(other_min);
+        // any axis without overlap separates the polygons
+        if overlap <= 0f32 {
+            return None;
+        }
+        if overlap < best_overlap {
+            best_overlap = overlap;
+            best_axis = axis;
+        }
+    }
+    if best_overlap == f32::INFINITY {
+        return None;
+    }
+    if (get_centroid(other_vertices) - get_centroid(vertices)).dot(best_axis) < 0f32 {
+        best_axis = -best_axis;
+    }
+    Some(best_axis * best_overlap)
+}
+
+/// Check if a point is inside or on the edge of a convex polygon of either winding.
+fn contains_point(vertices: &[Vector2], p: &Vector2) -> bool {
+    let mut sign = 0f32;
+    for (start, end) in vertices.iter().zip(vertices.iter().cycle().skip(1)) {
+        let edge = *end - *start;
+        let offset = *p - *start;
+        let cross = edge.x * offset.y - edge.y * offset.x;
+        if cross * sign < 0f32 {
+            return false;
+        }
+        if cross != 0f32 {
+            sign = cross;
+        }
+    }
+    true
+}
+
+/// Get the average of the corners of the area two convex polygons share, None if they don't overlap.
+fn get_polygon_contact_point(vertices: &[Vector2], other_vertices: &[Vector2]) -> Option<Vector2> {
+    let mut sum = Vector2::new(0f32, 0f32);
+    let mut count = 0;
+    // the corners are the vertices inside the other polygon and the points where the edges cross
+    for v in vertices
+        .iter()
+        .filter(|v| contains_point(other_vertices, v))
+    {
+        sum += *v;
+        count += 1;
+    }
+    for v in other_vertices
+        .iter()
+        .filter(|v| contains_point(vertices, v))
+    {
+        sum += *v;
+        count += 1;
+    }
+    for (start, end) in vertices.iter().zip(vertices.iter().cycle().skip(1)) {
+        let edge = *end - *start;
+        for (other_start, other_end) in other_vertices
+            .iter()
+            .zip(other_vertices.iter().cycle().skip(1))
+        {
+            let other_edge = *other_end - *other_start;
+            let denominator = edge.x * other_edge.y - edge.y * other_edge.x;
+            if denominator == 0f32 {
+                continue;
+            }
+            let offset = *other_start - *start;
+            let t = (offset.x * other_edge.y - offset.y * other_edge.x) / denominator;
+            let u = (offset.x * edge.y - offset.y * edge.x) / denominator;
+            if (0f32..=1f32).contains(&t) && (0f32..=1f32).contains(&u) {
+                sum += *start + edge * t;
+                count += 1;
+            }
         }
     }
+    if count == 0 {
+        return None;
+    }
+    Some(sum / count as f32)
+}
+
+/// Returns the shortest vector that separates a circle from a convex polygon, pointing from the circle to the polygon.
+fn get_circle_polygon_collision(
+    center: &Vector2,
+    radius: f32,
+    vertices: &[Vector2],
+) -> Option<Vector2> {
+    // the axis from the closest vertex catches collisions with the corners of the polygon
+    let closest = vertices.iter().copied().min_by(|v1, v2| {
+        (*v1 - *center)
+            .length_sqr()
+            .partial_cmp(&(*v2 - *center).length_sqr())
+            .unwrap()
+    })?;
+    let corner_axis = closest - *center;
+    let corner_axis = if corner_axis.length_sqr() > 0f32 {
+        Some(corner_axis.normalized())
+    } else {
+        None
+    };
+
+    let mut best_overlap = f32::INFINITY;
+    let mut best_axis = Vector2::new(0f32, 0f32);
+    for axis in get_edge_normals(vertices).chain(corner_axis) {
+        let (min, max) = project(vertices, axis);
+        let circle_center = center.dot(axis);
+        let overlap = max.min(circle_center + radius) - min.max(circle_center - radius);
+        if overlap <= 0f32 {
+            return None;
+        }
+        if overlap < best_overlap {
+            best_overlap = overlap;
+            best_axis = axis;
+        }
+    }
+    if (get_centroid(vertices) - *center).dot(best_axis) < 0f32 {
+        best_axis = -best_axis;
+    }
+    Some(best_axis * best_overlap)
+}
+
+/// Returns the shortest vector that separates two axis aligned rectangles, pointing from the first rectangle to the second.
+/// Only one component is non zero, so the collision is resolved along the shallowest axis.
+fn get_rect_collision(
+    pos: &Vector2,
+    size: &Vector2,
+    other_pos: &Vector2,
+    other_size: &Vector2,
+) -> Option<Vector2> {
+    let half = *size / 2f32;
+    let other_half = *other_size / 2f32;
+    let diff = (*other_pos + other_half) - (*pos + half);
+    let overlap_x = half.x + other_half.x - diff.x.abs();
+    let overlap_y = half.y + other_half.y - diff.y.abs();
+    // touching edges are not counted as a collision, there is nothing to resolve
+    if overlap_x <= 0f32 || overlap_y <= 0f32 {
+        return None;
+    }
+    if overlap_x < overlap_y {
+        Some(Vector2::new(overlap_x.copysign(diff.x), 0f32))
+    } else {
+        Some(Vector2::new(0f32, overlap_y.copysign(diff.y)))
+    }
+}
+
+/// Returns the shortest vector that separates a circle from an axis aligned rectangle, pointing from the circle to the rectangle.
+fn get_circle_rect_collision(
+    pos: &Vector2,
+    radius: f32,
+    rect_pos: &Vector2,
+    size: &Vector2,
+) -> Option<Vector2> {
+    let center = *pos + Vector2::one() * radius;
+    let closest = Vector2::new(
+        center.x.max(rect_pos.x).min(rect_pos.x + size.x),
+        center.y.max(rect_pos.y).min(rect_pos.y + size.y),
+    );
+    let diff = closest - center;
+    let dist_sq = diff.length_sqr();
+    if dist_sq > 0f32 {
+        if dist_sq >= radius * radius {
+            return None;
+        }
+        let dist = dist_sq.sqrt();
+        return Some(diff / dist * (radius - dist));
+    }
+
+    // the center is inside of the rectangle, so push it out through the closest side
+    let left = center.x - rect_pos.x;
+    let right = rect_pos.x + size.x - center.x;
+    let top = center.y - rect_pos.y;
+    let bottom = rect_pos.y + size.y - center.y;
+    let min = left.min(right).min(top).min(bottom);
+    if min == left {
+        Some(Vector2::new(left + radius, 0f32))
+    } else if min == right {
+        Some(Vector2::new(-(right + radius), 0f32))
+    } else if min == top {
+        Some(Vector2::new(0f32, top + radius))
+    } else {
+        Some(Vector2::new(0f32, -(bottom + radius)))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -175,8 +877,385 @@ impl AABB {
             && (map[3] || self.ly <= other.ry)
     }
 
+    /// Returns the distance along a ray where it enters the bounding box, otherwise None.
+    /// A ray that starts inside the bounding box enters it at distance zero.
+    pub fn raycast(&self, origin: &Vector2, direction: &Vector2, max_distance: f32) -> Option<f32> {
+        let mut enter = 0f32;
+        let mut exit = max_distance;
+        for (start, end, o, d) in [
+            (self.lx, self.rx, origin.x, direction.x),
+            (self.ly, self.ry, origin.y, direction.y),
+        ]
+        .iter()
+        {
+            if *d == 0f32 {
+                if o < start || o > end {
+                    return None;
+                }
+            } else {
+                let t1 = (start - o) / d;
+                let t2 = (end - o) / d;
+                enter = enter.max(t1.min(t2));
+                exit = exit.min(t1.max(t2));
+            }
+        }
+        if enter <= exit {
+            Some(enter)
+        } else {
+            None
+        }
+    }
+
     /// Check if the bounding box contains the given bounding box.
     pub fn contains(&self, other: &AABB) -> bool {
         self.rx >= other.rx && self.lx <= other.lx && self.ry >= other.ry && self.ly <= other.ly
     }
 }
+
+/// A set of collision layers stored as one bit per layer.
+/// Layers can be named with constants, for example `const PLAYER: LayerMask = LayerMask::layer(0);`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct LayerMask(pub u128);
+
+impl LayerMask {
+    /// No layers.
+    pub const NONE: LayerMask = LayerMask(0);
+    /// Every layer.
+    pub const ALL: LayerMask = LayerMask(u128::MAX);
+
+    /// A mask with only a single layer, panics if the layer isn't less than `LAYERS`.
+    pub const fn layer(layer: usize) -> LayerMask {
+        LayerMask(LayerMask::bit(layer))
+    }
+
+    /// A mask with all of the listed layers.
+    pub fn from_layers(layers: &[usize]) -> LayerMask {
+        layers
+            .iter()
+            .fold(LayerMask::NONE, |mask, layer| mask.with(*layer))
+    }
+
+    /// Adds a layer to the mask.
+    pub const fn with(self, layer: usize) -> LayerMask {
+        LayerMask(self.0 | LayerMask::bit(layer))
+    }
+
+    /// Removes a layer from the mask.
+    pub const fn without(self, layer: usize) -> LayerMask {
+        LayerMask(self.0 & !LayerMask::bit(layer))
+    }
+
+    /// Check if the mask has a layer.
+    pub const fn contains(&self, layer: usize) -> bool {
+        self.0 & LayerMask::bit(layer) != 0
+    }
+
+    /// Check if the masks share any layer.
+    pub const fn intersects(&self, other: &LayerMask) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    const fn bit(layer: usize) -> u128 {
+        assert!(layer < LAYERS, "the layer must be less than LAYERS");
+        1 << layer
+    }
+
+    /// Get the layers in the mask.
+    pub fn layers(&self) -> impl Iterator<Item = usize> {
+        let mut bits = self.0;
+        std::iter::from_fn(move || {
+            if bits == 0 {
+                return None;
+            }
+            let layer = bits.trailing_zeros() as usize;
+            // clear the lowest set bit
+            bits &= bits - 1;
+            Some(layer)
+        })
+    }
+}
+
+impl BitOr for LayerMask {
+    type Output = LayerMask;
+
+    fn bitor(self, other: LayerMask) -> LayerMask {
+        LayerMask(self.0 | other.0)
+    }
+}
+
+impl BitAnd for LayerMask {
+    type Output = LayerMask;
+
+    fn bitand(self, other: LayerMask) -> LayerMask {
+        LayerMask(self.0 & other.0)
+    }
+}
+
+impl Not for LayerMask {
+    type Output = LayerMask;
+
+    fn not(self) -> LayerMask {
+        LayerMask(!self.0)
+    }
+}
+
+/// A resource that controls which layers can collide with each other, on top of the masks of each collider.
+/// By default every layer collides with every other layer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerMatrix([LayerMask; LAYERS]);
+
+impl Default for LayerMatrix {
+    fn default() -> Self {
+        LayerMatrix([LayerMask::ALL; LAYERS])
+    }
+}
+
+impl LayerMatrix {
+    /// Set if two layers can collide, this works both ways.
+    pub fn set(&mut self, layer: usize, other_layer: usize, collides: bool) {
+        if collides {
+            self.0[layer] = self.0[layer].with(other_layer);
+            self.0[other_layer] = self.0[other_layer].with(layer);
+        } else {
+            self.0[layer] = self.0[layer].without(other_layer);
+            self.0[other_layer] = self.0[other_layer].without(layer);
+        }
+    }
+
+    /// Check if two layers can collide.
+    pub fn collides(&self, layer: usize, other_layer: usize) -> bool {
+        self.0[layer].contains(other_layer)
+    }
+
+    /// Get the layers that anything on the given layers can collide with.
+    /// A collider that isn't on any layer isn't limited by the matrix.
+    pub fn get_mask(&self, layers: &LayerMask) -> LayerMask {
+        if *layers == LayerMask::NONE {
+            return LayerMask::ALL;
+        }
+        layers
+            .layers()
+            .fold(LayerMask::NONE, |mask, layer| mask | self.0[layer])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(w: f32, h: f32) -> Shape {
+        Shape::RectangeCollider {
+            size: Vector2::new(w, h),
+        }
+    }
+
+    #[test]
+    fn touching_rects_do_not_collide() {
+        let a = rect(10f32, 10f32);
+        let b = rect(10f32, 10f32);
+        // sharing an edge on either axis, or only a corner
+        for other_pos in &[
+            Vector2::new(10f32, 0f32),
+            Vector2::new(0f32, 10f32),
+            Vector2::new(-10f32, 3f32),
+            Vector2::new(10f32, 10f32),
+        ] {
+            assert_eq!(
+                a.get_collision(&Vector2::new(0f32, 0f32), other_pos, &b),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn nested_rect_is_pushed_out_along_the_smallest_axis() {
+        let outer = rect(40f32, 20f32);
+        let inner = rect(10f32, 10f32);
+        // the inner rect is closer to the bottom than to the sides
+        let collision = outer.get_collision(
+            &Vector2::new(0f32, 0f32),
+            &Vector2::new(12f32, 8f32),
+            &inner,
+        );
+        // centers are 3 apart in y, so the overlap is 5 + 10 - 3 = 12, less than 5 + 20 - 3 = 22 in x
+        assert_eq!(collision, Some(Vector2::new(0f32, 12f32)));
+        assert_eq!(
+            inner.get_collision(
+                &Vector2::new(12f32, 8f32),
+                &Vector2::new(0f32, 0f32),
+                &outer
+            ),
+            Some(Vector2::new(0f32, -12f32))
+        );
+    }
+
+    #[test]
+    fn corner_overlap_uses_the_shallowest_axis() {
+        let a = rect(10f32, 10f32);
+        let b = rect(10f32, 10f32);
+        let origin = Vector2::new(0f32, 0f32);
+        // overlapping 2 in x and 7 in y
+        assert_eq!(
+            a.get_collision(&origin, &Vector2::new(8f32, 3f32), &b),
+            Some(Vector2::new(2f32, 0f32))
+        );
+        // overlapping 6 in x and 1 in y, towards the top left
+        assert_eq!(
+            a.get_collision(&origin, &Vector2::new(-4f32, -9f32), &b),
+            Some(Vector2::new(0f32, -1f32))
+        );
+    }
+
+    fn circle(radius: f32) -> Shape {
+        Shape::CircleCollider { radius }
+    }
+
+    fn triangle() -> Shape {
+        Shape::polygon(vec![
+            Vector2::new(0f32, 0f32),
+            Vector2::new(14f32, 3f32),
+            Vector2::new(5f32, 11f32),
+        ])
+    }
+
+    #[test]
+    fn fast_shapes_do_not_tunnel_through_thin_walls() {
+        let wall = rect(1f32, 100f32);
+        let wall_pos = Vector2::new(1000f32, -50f32);
+        let from = Vector2::new(0f32, 0f32);
+        let to = Vector2::new(2000f32, 0f32);
+        for shape in &[rect(4f32, 4f32), circle(2f32), triangle()] {
+            let (time, normal) = shape
+                .get_time_of_impact(&from, &to, &wall_pos, &wall)
+                .expect("the wall was skipped");
+            let bb = shape.get_bounding_box(&(from + (to - from) * time));
+            assert!((bb.rx - wall_pos.x).abs() < 1e-3, "{:?}", bb);
+            assert_eq!(normal, Vector2::new(-1f32, 0f32));
+        }
+    }
+
+    #[test]
+    fn paths_that_miss_have_no_time_of_impact() {
+        let wall = rect(1f32, 100f32);
+        let wall_pos = Vector2::new(1000f32, -50f32);
+        let from = Vector2::new(0f32, 60f32);
+        let to = Vector2::new(2000f32, 60f32);
+        for shape in &[rect(4f32, 4f32), circle(2f32), triangle()] {
+            assert_eq!(shape.get_time_of_impact(&from, &to, &wall_pos, &wall), None);
+        }
+    }
+
+    fn assert_close(a: Vector2, b: Vector2) {
+        assert!((a - b).length() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn contact_point_is_in_the_shared_area() {
+        let a = rect(10f32, 10f32);
+        let origin = Vector2::new(0f32, 0f32);
+        // resting on top of a wider rect, the contact is below the middle of the smaller one
+        let floor = rect(40f32, 10f32);
+        let floor_pos = Vector2::new(-15f32, 9f32);
+        let collision_vec = a.get_collision(&origin, &floor_pos, &floor).unwrap();
+        let point = a.get_contact_point(&origin, &floor_pos, &floor, collision_vec);
+        assert_close(point, Vector2::new(5f32, 9.5f32));
+        // overlapping at a corner
+        let corner_pos = Vector2::new(8f32, 6f32);
+        let collision_vec = a.get_collision(&origin, &corner_pos, &a).unwrap();
+        let point = a.get_contact_point(&origin, &corner_pos, &a, collision_vec);
+        assert_close(point, Vector2::new(9f32, 8f32));
+        // a circle touches halfway into the overlap
+        let ball = circle(5f32);
+        let ball_pos = Vector2::new(2f32, -8f32);
+        let collision_vec = ball.get_collision(&ball_pos, &origin, &a).unwrap();
+        let point = ball.get_contact_point(&ball_pos, &origin, &a, collision_vec);
+        assert_close(point, Vector2::new(7f32, 1f32));
+    }
+
+    #[test]
+    fn raycast_skips_degenerate_polygon_edges() {
+        // the first edge has no length
+        let vertices = [
+            Vector2::new(0f32, 0f32),
+            Vector2::new(0f32, 0f32),
+            Vector2::new(10f32, 0f32),
+            Vector2::new(10f32, 10f32),
+            Vector2::new(0f32, 10f32),
+        ];
+        let hit = get_polygon_raycast(
+            &vertices,
+            &Vector2::new(5f32, 15f32),
+            &Vector2::new(0f32, -1f32),
+            100f32,
+        );
+        assert_eq!(hit, Some((5f32, Vector2::new(0f32, 1f32))));
+    }
+
+    #[test]
+    #[should_panic]
+    fn layer_must_be_less_than_layers() {
+        LayerMask::layer(LAYERS);
+    }
+
+    #[test]
+    #[should_panic]
+    fn polygon_needs_three_vertices() {
+        Shape::polygon(vec![Vector2::new(0f32, 0f32), Vector2::new(1f32, 0f32)]);
+    }
+
+    /// Check that colliding a with b gives the opposite vector of colliding b with a, at positions all around b.
+    fn assert_symmetric(a: &Shape, b: &Shape) {
+        let b_pos = Vector2::new(0.5f32, 0.25f32);
+        let mut collisions = 0;
+        for x in -20..20 {
+            for y in -20..20 {
+                let a_pos = Vector2::new(x as f32 * 0.7f32, y as f32 * 0.7f32);
+                let forward = a.get_collision(&a_pos, &b_pos, b);
+                let backward = b.get_collision(&b_pos, &a_pos, a);
+                match (forward, backward) {
+                    (Some(forward), Some(backward)) => {
+                        assert!(
+                            (forward + backward).length() < 1e-4,
+                            "{:?} and {:?} at {:?}",
+                            forward,
+                            backward,
+                            a_pos
+                        );
+                        collisions += 1;
+                    }
+                    (None, None) => (),
+                    _ => panic!("{:?} and {:?} at {:?}", forward, backward, a_pos),
+                }
+            }
+        }
+        assert!(collisions > 0);
+    }
+
+    #[test]
+    fn circle_rect_is_symmetric() {
+        assert_symmetric(&circle(4f32), &rect(12f32, 8f32));
+    }
+
+    #[test]
+    fn circle_inside_rect_is_symmetric() {
+        let c = circle(2f32);
+        let r = rect(20f32, 10f32);
+        let rect_pos = Vector2::new(0f32, 0f32);
+        // the center is at (5, 6), closest to the bottom side
+        let circle_pos = Vector2::new(3f32, 4f32);
+        let forward = c.get_collision(&circle_pos, &rect_pos, &r);
+        let backward = r.get_collision(&rect_pos, &circle_pos, &c);
+        assert_eq!(forward, Some(Vector2::new(0f32, -6f32)));
+        assert_eq!(backward, forward.map(|v| -v));
+    }
+
+    #[test]
+    fn circle_polygon_is_symmetric() {
+        assert_symmetric(&circle(4f32), &triangle());
+    }
+
+    #[test]
+    fn rect_polygon_is_symmetric() {
+        assert_symmetric(&rect(12f32, 8f32), &triangle());
+    }
+}
@@ -0,0 +1,363 @@
+use crate::broad_phase::BroadPhaseKind;
+use crate::bvh::BuildStrategy;
+use crate::physics::{CombineRule, Material};
+use raylib::core::math::Vector2;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Physics settings that are read every frame. Change the resource in the world to change them at runtime.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhysicsConfig {
+    /// Acceleration applied to every unfrozen entity in pixels per second squared.
+    pub gravity: Vector2,
+    /// Fraction of the velocity that is kept every frame.
+    pub friction: f32,
+    /// How the restitution of two materials is combined.
+    pub restitution_combine: CombineRule,
+    /// How the friction of two materials is combined.
+    pub friction_combine: CombineRule,
+    /// The material of the walls around the world.
+    pub wall_material: Material,
+    /// Number of times the contact solver goes over every contact to fix the velocities.
+    pub velocity_iterations: usize,
+    /// Number of times the contact solver goes over every contact to push overlapping bodies apart.
+    pub position_iterations: usize,
+    /// Start the solver with the impulses of the last frame, which helps stacks settle.
+    pub warm_starting: bool,
+    /// Overlap in pixels that is allowed without being pushed apart, which stops resting bodies from jittering.
+    pub penetration_slop: f32,
+    /// Fraction of the remaining overlap that is removed by each position iteration.
+    pub position_correction: f32,
+    /// Bodies that hit each other slower than this in pixels per second do not bounce, which lets stacks come to rest.
+    pub restitution_threshold: f32,
+    /// How the bvh tree is split when it is first built.
+    pub bvh_build_strategy: BuildStrategy,
+    /// Which broad phase is built to find the entities that could be touching.
+    pub broad_phase: BroadPhaseKind,
+    /// Size in pixels of the cells of the grid broad phase, it works best a little larger than most entities.
+    pub grid_cell_size: f32,
+}
+
+impl Default for PhysicsConfig {
+    fn default() -> Self {
+        PhysicsConfig {
+            gravity: Vector2::new(0f32, 0f32),
+            friction: 1f32,
+            restitution_combine: CombineRule::Average,
+            friction_combine: CombineRule::Average,
+            wall_material: Material::default(),
+            velocity_iterations: 8,
+            position_iterations: 4,
+            warm_starting: true,
+            penetration_slop: 0.5f32,
+            position_correction: 0.2f32,
+            restitution_threshold: 20f32,
+            bvh_build_strategy: BuildStrategy::Median,
+            broad_phase: BroadPhaseKind::Bvh,
+            grid_cell_size: 64f32,
+        }
+    }
+}
+
+/// Settings used to create the engine.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngineConfig {
+    pub window_size: [i32; 2],
+    pub title: String,
+    pub physics: PhysicsConfig,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            window_size: [1400, 1000],
+            title: "Iron Oxide Engine".to_string(),
+            physics: PhysicsConfig::default(),
+        }
+    }
+}
+
+impl EngineConfig {
+    /// Load the config from a file, see `EngineConfig::parse` for the format.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<EngineConfig, ConfigError> {
+        let text = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        EngineConfig::parse(&text)
+    }
+
+    /// Parse a config made of `key = value` lines. Missing keys keep their default value.
+    /// Lines starting with `#` are ignored, and vectors are written as `x, y`.
+    /// Deprecated keys are ignored, `EngineConfig::parse_with_warnings` also returns where they were used.
+    pub fn parse(text: &str) -> Result<EngineConfig, ConfigError> {
+        EngineConfig::parse_with_warnings(text).map(|(config, _)| config)
+    }
+
+    /// Parse a config like `EngineConfig::parse`, along with a warning for every deprecated key.
+    pub fn parse_with_warnings(
+        text: &str,
+    ) -> Result<(EngineConfig, Vec<ConfigWarning>), ConfigError> {
+        let mut config = EngineConfig::default();
+        let mut warnings = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: &str| ConfigError::Parse {
+                line: i + 1,
+                message: message.to_string(),
+            };
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(error("expected `key = value`")),
+            };
+            match key {
+                "window_size" => {
+                    let size =
+                        parse_pair(value).ok_or_else(|| error("expected `width, height`"))?;
+                    config.window_size = [size[0] as i32, size[1] as i32];
+                }
+                "title" => config.title = value.to_string(),
+                "gravity" => {
+                    let gravity = parse_pair(value).ok_or_else(|| error("expected `x, y`"))?;
+                    config.physics.gravity = Vector2::new(gravity[0], gravity[1]);
+                }
+                "friction" => {
+                    config.physics.friction =
+                        value.parse().map_err(|_| error("expected a number"))?
+                }
+                "restitution_combine" => {
+                    config.physics.restitution_combine = parse_combine_rule(value)
+                        .ok_or_else(|| error("expected min, max, average or multiply"))?
+                }
+                "friction_combine" => {
+                    config.physics.friction_combine = parse_combine_rule(value)
+                        .ok_or_else(|| error("expected min, max, average or multiply"))?
+                }
+                "wall_restitution" => {
+                    config.physics.wall_material.restitution =
+                        value.parse().map_err(|_| error("expected a number"))?
+                }
+                "wall_static_friction" => {
+                    config.physics.wall_material.static_friction =
+                        value.parse().map_err(|_| error("expected a number"))?
+                }
+                "wall_dynamic_friction" => {
+                    config.physics.wall_material.dynamic_friction =
+                        value.parse().map_err(|_| error("expected a number"))?
+                }
+                "velocity_iterations" => {
+                    config.physics.velocity_iterations = value
+                        .parse()
+                        .map_err(|_| error("expected a whole number"))?
+                }
+                "position_iterations" => {
+                    config.physics.position_iterations = value
+                        .parse()
+                        .map_err(|_| error("expected a whole number"))?
+                }
+                "warm_starting" => {
+                    config.physics.warm_starting =
+                        value.parse().map_err(|_| error("expected true or false"))?
+                }
+                "penetration_slop" => {
+                    config.physics.penetration_slop =
+                        value.parse().map_err(|_| error("expected a number"))?
+                }
+                "position_correction" => {
+                    config.physics.position_correction =
+                        value.parse().map_err(|_| error("expected a number"))?
+                }
+                "restitution_threshold" => {
+                    config.physics.restitution_threshold =
+                        value.parse().map_err(|_| error("expected a number"))?
+                }
+                "bvh_build_strategy" => {
+                    config.physics.bvh_build_strategy = parse_build_strategy(value)
+                        .ok_or_else(|| error("expected median or sah"))?
+                }
+                "broad_phase" => {
+                    config.physics.broad_phase = parse_broad_phase(value)
+                        .ok_or_else(|| error("expected bvh, grid or sweep_and_prune"))?
+                }
+                "grid_cell_size" => {
+                    config.physics.grid_cell_size = value
+                        .parse()
+                        .ok()
+                        .filter(|size: &f32| size.is_finite() && *size > 0f32)
+                        .ok_or_else(|| error("expected a positive number"))?
+                }
+                // replaced by the physics materials, kept so old configs still load
+                "collision_friction" => warnings.push(ConfigWarning {
+                    line: i + 1,
+                    message: "collision_friction is deprecated and ignored, use the materials"
+                        .to_string(),
+                }),
+                // the bvh tree is updated every frame now, kept so old configs still load
+                "min_bvh_update_time" => warnings.push(ConfigWarning {
+                    line: i + 1,
+                    message: "min_bvh_update_time is deprecated and ignored".to_string(),
+                }),
+                _ => return Err(error("unknown key")),
+            }
+        }
+        Ok((config, warnings))
+    }
+}
+
+/// Parses two comma separated numbers.
+fn parse_pair(value: &str) -> Option<[f32; 2]> {
+    let mut iter = value.split(',').map(|v| v.trim().parse::<f32>());
+    match (iter.next(), iter.next(), iter.next()) {
+        (Some(Ok(x)), Some(Ok(y)), None) => Some([x, y]),
+        _ => None,
+    }
+}
+
+/// Parses the name of a combine rule.
+fn parse_combine_rule(value: &str) -> Option<CombineRule> {
+    match value {
+        "min" => Some(CombineRule::Min),
+        "max" => Some(CombineRule::Max),
+        "average" => Some(CombineRule::Average),
+        "multiply" => Some(CombineRule::Multiply),
+        _ => None,
+    }
+}
+
+/// Parses the name of a bvh build strategy.
+fn parse_build_strategy(value: &str) -> Option<BuildStrategy> {
+    match value {
+        "median" => Some(BuildStrategy::Median),
+        "sah" => Some(BuildStrategy::Sah),
+        _ => None,
+    }
+}
+
+/// Parses the name of a broad phase.
+fn parse_broad_phase(value: &str) -> Option<BroadPhaseKind> {
+    match value {
+        "bvh" => Some(BroadPhaseKind::Bvh),
+        "grid" => Some(BroadPhaseKind::Grid),
+        "sweep_and_prune" => Some(BroadPhaseKind::SweepAndPrune),
+        _ => None,
+    }
+}
+
+/// An error that happened while loading a config.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "failed to read config: {}", err),
+            ConfigError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A key in a config that still loads but should be changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigWarning {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error_line(text: &str) -> Option<usize> {
+        match EngineConfig::parse(text) {
+            Err(ConfigError::Parse { line, .. }) => Some(line),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn valid_keys_are_parsed() {
+        let config = EngineConfig::parse(
+            "# a comment\n\
+             window_size = 800, 600\n\
+             title = Test\n\
+             \n\
+             gravity = 0, 9.8\n\
+             friction = 0.99\n\
+             restitution_combine = max\n\
+             friction_combine = min\n\
+             wall_restitution = 0.5\n\
+             velocity_iterations = 10\n\
+             warm_starting = false\n\
+             position_correction = 0.4\n\
+             restitution_threshold = 5\n\
+             bvh_build_strategy = sah\n\
+             broad_phase = grid\n\
+             grid_cell_size = 32",
+        )
+        .unwrap();
+        assert_eq!(config.window_size, [800, 600]);
+        assert_eq!(config.title, "Test");
+        assert_eq!(config.physics.gravity, Vector2::new(0f32, 9.8f32));
+        assert_eq!(config.physics.friction, 0.99f32);
+        assert_eq!(config.physics.restitution_combine, CombineRule::Max);
+        assert_eq!(config.physics.friction_combine, CombineRule::Min);
+        assert_eq!(config.physics.wall_material.restitution, 0.5f32);
+        assert_eq!(config.physics.velocity_iterations, 10);
+        assert!(!config.physics.warm_starting);
+        assert_eq!(config.physics.position_correction, 0.4f32);
+        assert_eq!(config.physics.restitution_threshold, 5f32);
+        assert_eq!(config.physics.bvh_build_strategy, BuildStrategy::Sah);
+        assert_eq!(config.physics.broad_phase, BroadPhaseKind::Grid);
+        assert_eq!(config.physics.grid_cell_size, 32f32);
+    }
+
+    #[test]
+    fn missing_keys_keep_their_default() {
+        assert_eq!(
+            EngineConfig::parse("").unwrap().physics,
+            PhysicsConfig::default()
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_errors() {
+        assert_eq!(parse_error_line("friction = 1\nfrction = 1"), Some(2));
+    }
+
+    #[test]
+    fn bad_values_are_errors() {
+        assert_eq!(parse_error_line("window_size = 800"), Some(1));
+        assert_eq!(parse_error_line("gravity = 1, 2, 3"), Some(1));
+        assert_eq!(parse_error_line("friction = fast"), Some(1));
+        assert_eq!(parse_error_line("velocity_iterations = 2.5"), Some(1));
+        assert_eq!(parse_error_line("warm_starting = yes"), Some(1));
+        assert_eq!(parse_error_line("broad_phase = quadtree"), Some(1));
+        assert_eq!(parse_error_line("grid_cell_size = 0"), Some(1));
+        assert_eq!(parse_error_line("grid_cell_size = inf"), Some(1));
+        assert_eq!(parse_error_line("no equals sign"), Some(1));
+    }
+
+    #[test]
+    fn deprecated_keys_are_warnings() {
+        let (config, warnings) = EngineConfig::parse_with_warnings(
+            "collision_friction = 0.9\nfriction = 0.5\nmin_bvh_update_time = 0.25",
+        )
+        .unwrap();
+        assert_eq!(config.physics.friction, 0.5f32);
+        let lines: Vec<_> = warnings.iter().map(|w| w.line).collect();
+        assert_eq!(lines, vec![1, 3]);
+    }
+}
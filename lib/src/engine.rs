@@ -0,0 +1,322 @@
+use crate::broad_phase::{BroadPhaseKind, DynBroadPhase};
+use crate::collider;
+use crate::config::{EngineConfig, PhysicsConfig};
+use crate::events;
+use crate::physics;
+use crate::renderer;
+use crate::sensor;
+use crate::solver;
+use crate::utils;
+use crate::{BvhData, RenderingData};
+use crate::{CollideEnities, RunSensors, UpdatePhysics};
+use raylib::prelude::*;
+use specs::shred::Resource;
+use specs::{Component, Dispatcher, DispatcherBuilder, Join, System, World, WorldExt, Write};
+
+/// Collects the window, world and systems before the engine is started.
+pub struct EngineBuilder<'a, 'b> {
+    window: Option<(RaylibHandle, RaylibThread)>,
+    world: World,
+    dispatcher: DispatcherBuilder<'a, 'b>,
+}
+
+impl<'a, 'b> EngineBuilder<'a, 'b> {
+    /// Creates a builder that opens a window.
+    pub fn new() -> EngineBuilder<'a, 'b> {
+        EngineBuilder::from_config(EngineConfig::default())
+    }
+
+    /// Creates a builder that opens a window with the given settings.
+    pub fn from_config(config: EngineConfig) -> EngineBuilder<'a, 'b> {
+        let (rl, thread) = raylib::init()
+            .resizable()
+            .size(config.window_size[0], config.window_size[1])
+            .title(&config.title)
+            .build();
+
+        let mut world = create_world(config.physics);
+        world.insert([rl.get_screen_width(), rl.get_screen_height()]);
+        EngineBuilder {
+            window: Some((rl, thread)),
+            world,
+            dispatcher: create_dispatcher(),
+        }
+    }
+
+    /// Creates a builder that runs without a window, useful for tests and servers.
+    pub fn headless() -> EngineBuilder<'a, 'b> {
+        EngineBuilder::headless_from_config(EngineConfig::default())
+    }
+
+    /// Creates a builder that runs without a window with the given settings.
+    /// The window size is still used as the bounds of the world.
+    pub fn headless_from_config(config: EngineConfig) -> EngineBuilder<'a, 'b> {
+        let mut world = create_world(config.physics);
+        world.insert(config.window_size);
+        EngineBuilder {
+            window: None,
+            world,
+            dispatcher: create_dispatcher(),
+        }
+    }
+
+    /// Adds a system that runs after the given dependencies.
+    pub fn with_system<S>(mut self, system: S, name: &str, dep: &[&str]) -> EngineBuilder<'a, 'b>
+    where
+        S: for<'c> System<'c> + Send + 'a,
+    {
+        self.add_system(system, name, dep);
+        self
+    }
+
+    /// Adds a system that runs after the given dependencies.
+    pub fn add_system<S>(&mut self, system: S, name: &str, dep: &[&str])
+    where
+        S: for<'c> System<'c> + Send + 'a,
+    {
+        self.dispatcher.add(system, name, dep);
+    }
+
+    /// Registers a component that is not used by any system.
+    pub fn with_component<C>(mut self) -> EngineBuilder<'a, 'b>
+    where
+        C: Component,
+        C::Storage: Default,
+    {
+        self.register::<C>();
+        self
+    }
+
+    /// Registers a component that is not used by any system.
+    pub fn register<C>(&mut self)
+    where
+        C: Component,
+        C::Storage: Default,
+    {
+        self.world.register::<C>();
+    }
+
+    /// Inserts a resource into the world.
+    pub fn with_resource<R: Resource>(mut self, resource: R) -> EngineBuilder<'a, 'b> {
+        self.insert_resource(resource);
+        self
+    }
+
+    /// Inserts a resource into the world.
+    pub fn insert_resource<R: Resource>(&mut self, resource: R) {
+        self.world.insert(resource);
+    }
+
+    /// Chooses which broad phase finds the entities that could be touching.
+    pub fn with_broad_phase(mut self, broad_phase: BroadPhaseKind) -> EngineBuilder<'a, 'b> {
+        self.set_broad_phase(broad_phase);
+        self
+    }
+
+    /// Chooses which broad phase finds the entities that could be touching.
+    pub fn set_broad_phase(&mut self, broad_phase: BroadPhaseKind) {
+        self.world.write_resource::<PhysicsConfig>().broad_phase = broad_phase;
+    }
+
+    /// Finalizes the systems and creates the engine.
+    pub fn build(mut self) -> Engine<'a, 'b> {
+        let mut dispatcher = self.dispatcher.build();
+        dispatcher.setup(&mut self.world);
+        Engine {
+            window: self.window,
+            world: self.world,
+            dispatcher,
+        }
+    }
+
+    /// Splits a windowed builder into the parts used by the tuple based api.
+    pub(crate) fn into_parts(
+        self,
+    ) -> (RaylibHandle, RaylibThread, World, DispatcherBuilder<'a, 'b>) {
+        let (rl, thread) = self.window.expect("the engine is headless");
+        (rl, thread, self.world, self.dispatcher)
+    }
+
+    /// Splits a headless builder into the parts used by the tuple based api.
+    pub(crate) fn into_headless_parts(self) -> (World, DispatcherBuilder<'a, 'b>) {
+        (self.world, self.dispatcher)
+    }
+}
+
+impl<'a, 'b> Default for EngineBuilder<'a, 'b> {
+    fn default() -> Self {
+        EngineBuilder::new()
+    }
+}
+
+/// Owns the window, world and systems, and runs the frame loop.
+pub struct Engine<'a, 'b> {
+    window: Option<(RaylibHandle, RaylibThread)>,
+    world: World,
+    dispatcher: Dispatcher<'a, 'b>,
+}
+
+impl<'a, 'b> Engine<'a, 'b> {
+    /// Get the world.
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    /// Get the world mutably, for inserting resources and creating entities.
+    pub fn world_mut(&mut self) -> &mut World {
+        &mut self.world
+    }
+
+    /// Get the raylib handle. Panics if the engine is headless.
+    pub fn rl(&self) -> &RaylibHandle {
+        &self.window.as_ref().expect("the engine is headless").0
+    }
+
+    /// Get the raylib handle and thread, for loading textures. Panics if the engine is headless.
+    pub fn window(&mut self) -> (&mut RaylibHandle, &RaylibThread) {
+        let (rl, thread) = self.window.as_mut().expect("the engine is headless");
+        (rl, thread)
+    }
+
+    /// Check if the engine is running without a window.
+    pub fn is_headless(&self) -> bool {
+        self.window.is_none()
+    }
+
+    /// Check if the window has been closed. Headless engines never close.
+    pub fn should_close(&self) -> bool {
+        match &self.window {
+            Some((rl, _)) => rl.window_should_close(),
+            None => false,
+        }
+    }
+
+    /// Run a single frame and draw it. Panics if the engine is headless.
+    pub fn update(&mut self, callback: impl FnMut(&mut World, &mut RaylibDrawHandle)) {
+        let (rl, thread) = self.window.as_mut().expect("the engine is headless");
+        update_frame(rl, thread, &mut self.world, &mut self.dispatcher, callback);
+    }
+
+    /// Advance the world by a fixed amount of time without drawing anything.
+    pub fn step(&mut self, dt: f32) {
+        step_frame(&mut self.world, &mut self.dispatcher, dt);
+    }
+
+    /// Run frames until the window is closed. Panics if the engine is headless.
+    pub fn run(&mut self, mut callback: impl FnMut(&mut World, &mut RaylibDrawHandle)) {
+        while !self.should_close() {
+            self.update(&mut callback);
+        }
+    }
+}
+
+/// Creates a world with all of the engine's components and resources registered.
+fn create_world(physics_config: PhysicsConfig) -> World {
+    let broad_phase: Option<DynBroadPhase> = None;
+
+    let mut world = World::new();
+    world.register::<utils::Position>();
+    world.register::<utils::Rotation>();
+    world.register::<utils::Collisions>();
+    world.register::<physics::Physics>();
+    world.register::<collider::Collider>();
+    world.register::<renderer::Renderer>();
+    world.register::<utils::Frozen>();
+    world.register::<sensor::Sensor>();
+    world.insert(utils::Delta(0.00));
+    world.insert(physics_config);
+    world.insert(broad_phase);
+    world.insert(solver::ContactCache::default());
+    world.insert(collider::LayerMatrix::default());
+    world.insert(events::EventChannel::<events::CollisionEvent>::new());
+    world
+}
+
+/// Creates a dispatcher builder with the engine's physics systems.
+fn create_dispatcher<'a, 'b>() -> DispatcherBuilder<'a, 'b> {
+    DispatcherBuilder::new()
+        .with(UpdatePhysics, "update_physics", &[])
+        .with(
+            CollideEnities::default(),
+            "collide_entities",
+            &["update_physics"],
+        )
+        .with(RunSensors::default(), "run_sensors", &["collide_entities"])
+}
+
+/// Runs the systems, draws the world and updates the delta time.
+pub(crate) fn update_frame(
+    rl: &mut RaylibHandle,
+    thread: &RaylibThread,
+    world: &mut World,
+    dispatcher: &mut Dispatcher,
+    mut callback: impl FnMut(&mut World, &mut RaylibDrawHandle),
+) {
+    // update screen size
+    if rl.is_window_resized() {
+        let mut size = world.write_resource::<[i32; 2]>();
+        *size = [rl.get_screen_width(), rl.get_screen_height()]
+    }
+
+    run_systems(world, dispatcher);
+
+    // draw everything
+    {
+        let mut d = rl.begin_drawing(thread);
+        d.clear_background(Color::WHITE);
+
+        {
+            let mut system_data: RenderingData = world.system_data();
+            for data in (
+                &mut system_data.0,
+                &system_data.1,
+                (&system_data.2).maybe(),
+                (&system_data.3).maybe(),
+                (&system_data.4).maybe(),
+            )
+                .join()
+            {
+                let (r, pos, _phys, _col, rot) = data;
+                r.render_rotated(&mut d, pos, rot.map_or(0f32, |r| r.0));
+            }
+        }
+        callback(world, &mut d);
+
+        d.draw_fps(0, 0);
+    }
+
+    // update the delta time
+    {
+        let mut delta = world.write_resource::<utils::Delta>();
+        *delta = utils::Delta(rl.get_frame_time());
+    }
+}
+
+/// Sets the delta time and runs the systems without drawing.
+pub(crate) fn step_frame(world: &mut World, dispatcher: &mut Dispatcher, dt: f32) {
+    {
+        let mut delta = world.write_resource::<utils::Delta>();
+        *delta = utils::Delta(dt);
+    }
+
+    run_systems(world, dispatcher);
+}
+
+/// Maintains the world, builds or syncs the broad phase and dispatches all of the systems.
+fn run_systems(world: &mut World, dispatcher: &mut Dispatcher) {
+    world.maintain();
+
+    // update the broad phase
+    // this must happen after maintain (so that deletes get processed) and before updates (so that no new deletes are queued)
+    {
+        let config = world.read_resource::<PhysicsConfig>().clone();
+        let bvh_data: BvhData = world.system_data();
+        let mut broad_phase_write: Write<Option<DynBroadPhase>> = world.system_data();
+        match &mut *broad_phase_write {
+            Some(broad_phase) => crate::sync_broad_phase(broad_phase.as_mut(), &bvh_data),
+            None => *broad_phase_write = crate::create_broad_phase(bvh_data, &config),
+        }
+    }
+
+    dispatcher.dispatch(world);
+}
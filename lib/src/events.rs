@@ -0,0 +1,38 @@
+use raylib::core::math::Vector2;
+use specs::Entity;
+
+pub use specs::shrev::{EventChannel, ReaderId};
+
+/// Information about two entities that are touching.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CollisionData {
+    /// The two entities, the first always has the smaller id.
+    pub entities: (Entity, Entity),
+    /// Unit vector pointing from the first entity towards the second.
+    pub normal: Vector2,
+    /// How far the entities overlap along the normal.
+    pub depth: f32,
+}
+
+/// An event sent through the `EventChannel<CollisionEvent>` resource.
+/// Register a reader with `EventChannel::register_reader` to receive them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CollisionEvent {
+    /// The entities started touching this frame.
+    CollisionStarted(CollisionData),
+    /// The entities were touching last frame and still are.
+    CollisionPersisted(CollisionData),
+    /// The entities stopped touching, the data is from the last frame they touched.
+    CollisionEnded(CollisionData),
+}
+
+impl CollisionEvent {
+    /// Get the data of the collision.
+    pub fn data(&self) -> &CollisionData {
+        match self {
+            CollisionEvent::CollisionStarted(data) => data,
+            CollisionEvent::CollisionPersisted(data) => data,
+            CollisionEvent::CollisionEnded(data) => data,
+        }
+    }
+}
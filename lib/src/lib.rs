@@ -3,27 +3,35 @@ extern crate lazy_static;
 pub use rand;
 pub use raylib;
 pub use raylib::prelude::*;
+pub use rayon;
 pub use rayon::prelude::*;
 pub use specs::Dispatcher;
 pub use specs::DispatcherBuilder;
 
+use specs::shrev::ReaderId;
+use specs::Entity;
+use specs::SystemData;
 pub use specs::{
     Builder, Entities, Join, LazyUpdate, ParJoin, Read, ReadStorage, System, World, WorldExt,
     Write, WriteStorage,
 };
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
+pub use config::{EngineConfig, PhysicsConfig};
+pub use engine::{Engine, EngineBuilder};
+
+pub mod broad_phase;
 pub mod bvh;
 pub mod collider;
+pub mod config;
+pub mod engine;
+pub mod events;
 pub mod physics;
 pub mod renderer;
+pub mod sensor;
+pub mod solver;
 pub mod utils;
 
-const COLLISION_FRICTION: f32 = 1f32;
-const FRICTION: f32 = 1f32;
-const GRAVITY: f32 = 0f32;
-const MIN_BHV_UPDATE_TIME: f32 = 0.25f32;
-const WINDOW_SIZE: [i32; 2] = [1400, 1000];
 lazy_static! {
     static ref HS1: HashSet<i8> = vec![0].into_iter().collect();
     static ref HS2: HashSet<i8> = vec![1].into_iter().collect();
@@ -34,12 +42,14 @@ pub type RenderingData<'a> = (
     ReadStorage<'a, utils::Position>,
     ReadStorage<'a, physics::Physics>,
     WriteStorage<'a, collider::Collider>,
+    ReadStorage<'a, utils::Rotation>,
 );
 
 pub type BvhData<'a> = (
     Entities<'a>,
     ReadStorage<'a, utils::Position>,
     ReadStorage<'a, collider::Collider>,
+    ReadStorage<'a, utils::Rotation>,
 );
 
 /// Handles updating the physics of entities.
@@ -47,184 +57,504 @@ struct UpdatePhysics;
 
 impl<'a> System<'a> for UpdatePhysics {
     type SystemData = (
-        Write<'a, Option<bvh::BVHTree>>,
+        Write<'a, Option<broad_phase::DynBroadPhase>>,
         Entities<'a>,
         ReadStorage<'a, collider::Collider>,
         Read<'a, utils::Delta>,
+        Read<'a, config::PhysicsConfig>,
         WriteStorage<'a, utils::Position>,
         WriteStorage<'a, physics::Physics>,
+        WriteStorage<'a, utils::Rotation>,
         ReadStorage<'a, utils::Frozen>,
+        Read<'a, collider::LayerMatrix>,
     );
 
     fn run(
         &mut self,
-        (mut bvh_tree, ents, col, delta, mut pos, mut phys, frozen): Self::SystemData,
+        (
+            mut broad_phase,
+            ents,
+            col,
+            delta,
+            config,
+            mut pos,
+            mut phys,
+            mut rot,
+            frozen,
+            layer_matrix,
+        ): Self::SystemData,
     ) {
-        (&mut phys, !&frozen).par_join().for_each(|(phys, ())| {
-            phys.velocity.y += GRAVITY;
-            phys.velocity *= FRICTION;
-        });
-
-        // todo: make this parrelel
-        if let Some(ref mut bvh) = *bvh_tree {
-            for (pos, phys, col_m, ent, ()) in
-                (&mut pos, &mut phys, (&col).maybe(), &ents, !&frozen).join()
+        (&mut phys, !&frozen)
+            .par_join()
+            .filter(|(phys, ())| phys.body_type == physics::BodyType::Dynamic)
+            .for_each(|(phys, ())| {
+                phys.velocity += config.gravity * delta.0;
+                phys.velocity *= config.friction;
+            });
+
+        if let Some(ref mut broad_phase) = *broad_phase {
+            // the broad phase is updated after the swept bodies are checked against where everything started
+            let mut start_positions = Vec::new();
+            let mut updates = Vec::new();
+            let mut swept = Vec::new();
+            for (pos, phys, col_m, rot_m, ent, ()) in (
+                &mut pos,
+                &mut phys,
+                (&col).maybe(),
+                (&mut rot).maybe(),
+                &ents,
+                !&frozen,
+            )
+                .join()
             {
+                // static bodies never move, so their place in the bvh tree doesn't need updating
+                if phys.body_type == physics::BodyType::Static {
+                    continue;
+                }
                 let old_pos = pos.0;
-                phys.update(&mut pos.0, delta.0);
+                let id = ent.id() as usize;
+                if start_positions.len() <= id {
+                    start_positions.resize(id + 1, None);
+                }
+                start_positions[id] = Some(old_pos);
+                phys.update(&mut pos.0, delta.0, &config);
+                let rotation = match rot_m {
+                    Some(rot) => {
+                        phys.update_rotation(&mut rot.0, delta.0, &config);
+                        rot.0
+                    }
+                    None => 0f32,
+                };
                 if let Some(col) = col_m {
-                    bvh.update(
-                        (&col.get_bounding_box(&old_pos), ent.id() as u32),
-                        (&col.get_bounding_box(&pos.0), ent.id() as u32),
-                    );
+                    if phys.ccd && col.physics_collider {
+                        swept.push((ent, old_pos));
+                        continue;
+                    }
+                    // the position from the last frame may have been changed by the solver, so the current box is used as is
+                    updates.push((
+                        ent.id(),
+                        col.get_bounding_box_rotated(&pos.0, rotation),
+                        pos.0 - old_pos,
+                    ));
                 }
             }
-        }
-    }
-}
 
-/// Handles shrinking the BVH tree after moving entities.
-struct ShrinkBvh;
-
-impl<'a> System<'a> for ShrinkBvh {
-    type SystemData = Write<'a, Option<bvh::BVHTree>>;
+            // continuous collision detection moves the body only until it hits something
+            for (ent, old_pos) in swept {
+                let collider = col.get(ent).unwrap();
+                let new_pos = pos.get(ent).unwrap().0;
+                let rotation = rot.get(ent).map_or(0f32, |r| r.0);
+                let shape = collider.shape.rotated(rotation);
+                let swept_bb = shape
+                    .get_bounding_box(&old_pos)
+                    .get_union(&shape.get_bounding_box(&new_pos));
+                let (impact, normal) = broad_phase
+                    .query_rect(&swept_bb, &get_query_mask(collider, &layer_matrix))
+                    .into_iter()
+                    .filter(|id| *id != ent.id())
+                    .filter_map(|id| {
+                        let other = ents.entity(id);
+                        let other_col = col
+                            .get(other)
+                            .filter(|c| c.physics_collider && ents.is_alive(other))?;
+                        let other_shape = other_col
+                            .shape
+                            .rotated(rot.get(other).map_or(0f32, |r| r.0));
+                        // bodies that moved this step are swept against where they started
+                        let other_pos = match start_positions.get(id as usize) {
+                            Some(Some(start)) => *start,
+                            _ => pos.get(other)?.0,
+                        };
+                        shape.get_time_of_impact(&old_pos, &new_pos, &other_pos, &other_shape)
+                    })
+                    // anything it was already touching is left to the solver
+                    .filter(|(time, _)| *time > 0f32)
+                    .fold((1f32, None), |first, (time, normal)| {
+                        if time < first.0 {
+                            (time, Some(normal))
+                        } else {
+                            first
+                        }
+                    });
+
+                let path = new_pos - old_pos;
+                let clamped_pos = if impact < 1f32 {
+                    // move a little into the collider so the solver sees the contact
+                    old_pos
+                        + path * impact
+                        + path.normalized()
+                            * config.penetration_slop.min(path.length() * (1f32 - impact))
+                } else {
+                    new_pos
+                };
+                pos.get_mut(ent).unwrap().0 = clamped_pos;
+                // stop moving into whatever was hit, the normal points away from it
+                if let (Some(normal), Some(phys)) = (normal, phys.get_mut(ent)) {
+                    let speed = phys.velocity.dot(normal);
+                    if speed < 0f32 {
+                        phys.velocity -= normal * speed;
+                    }
+                }
+                updates.push((
+                    ent.id(),
+                    collider.get_bounding_box_rotated(&clamped_pos, rotation),
+                    clamped_pos - old_pos,
+                ));
+            }
 
-    fn run(&mut self, mut bvh_tree: Self::SystemData) {
-        // todo: make this parrelel
-        if let Some(ref mut bvh) = *bvh_tree {
-            bvh.shrink();
+            for (id, bounding_box, displacement) in updates {
+                broad_phase.update(id, &bounding_box, displacement);
+            }
         }
     }
 }
 
-/// Handles colliding with the walls.
-struct CollideBounds;
-
-impl<'a> System<'a> for CollideBounds {
-    type SystemData = (
-        Read<'a, [i32; 2]>,
-        WriteStorage<'a, utils::Position>,
-        ReadStorage<'a, collider::Collider>,
-        WriteStorage<'a, physics::Physics>,
-        ReadStorage<'a, utils::Frozen>,
-    );
-
-    fn run(&mut self, (size, mut pos, col, mut phys, frozen): Self::SystemData) {
-        (&mut pos, &col, &mut phys, !&frozen)
-            .par_join()
-            .filter(|(_, col, _, ())| col.physics_collider)
-            .for_each(|(pos, col, phys, ())| {
-                let overlap_vec =
-                    col.get_collision_bounds(&pos.0, [0.0, 0.0, size[0] as f32, size[1] as f32]);
-                if let Some(unwraped) = overlap_vec {
-                    phys.collide_bound(&mut pos.0, unwraped);
-                }
-            });
-    }
-}
-
 /// Handles colliding with other entities.
-struct CollideEnities;
+#[derive(Default)]
+struct CollideEnities {
+    /// The pairs of entities that were touching last frame, an entity whose id was reused is a different entity.
+    touching: HashMap<(Entity, Entity), events::CollisionData>,
+}
 
 impl<'a> System<'a> for CollideEnities {
     type SystemData = (
-        Read<'a, Option<bvh::BVHTree>>,
+        Read<'a, Option<broad_phase::DynBroadPhase>>,
         WriteStorage<'a, utils::Position>,
         ReadStorage<'a, collider::Collider>,
         WriteStorage<'a, physics::Physics>,
         Entities<'a>,
         WriteStorage<'a, utils::Collisions>,
         ReadStorage<'a, utils::Frozen>,
+        Read<'a, config::PhysicsConfig>,
+        ReadStorage<'a, utils::Rotation>,
+        Write<'a, solver::ContactCache>,
+        Read<'a, [i32; 2]>,
+        Write<'a, events::EventChannel<events::CollisionEvent>>,
+        Read<'a, collider::LayerMatrix>,
     );
 
     fn run(&mut self, mut data: Self::SystemData) {
-        let bvh_tree = data.0;
-        let entity_data = (
+        let broad_phase = data.0;
+        let config = data.7;
+        let mut cache = data.9;
+        let size = data.10;
+        let mut collision_events = data.11;
+        let layer_matrix = data.12;
+        let mut entity_data = (
             &mut data.1,
             &data.2,
             (&mut data.3).maybe(),
             &data.4,
             &mut data.5,
-            !&data.6,
+            (&data.6).maybe(),
+            (&data.8).maybe(),
         )
             .join()
             .collect::<Vec<_>>();
 
-        if let Some(ref bvh) = *bvh_tree {
+        if let Some(ref broad_phase) = *broad_phase {
             let mut old_data = Vec::new();
 
             for e in &entity_data {
                 let id = e.3.id() as usize;
                 old_data.resize(id + 1, None);
-                old_data[id] = Some((e.0 .0, e.2.as_deref().cloned(), e.1.clone()));
+                // frozen entities are treated like static bodies
+                let phys = match (e.2.as_deref(), e.5) {
+                    (Some(phys), Some(_)) => {
+                        Some(phys.clone().with_body_type(physics::BodyType::Static))
+                    }
+                    (phys, _) => phys.cloned(),
+                };
+                // the shape is rotated once here instead of for every pair it is in
+                let rotation = e.6.map_or(0f32, |r| r.0);
+                let collider = collider::Collider {
+                    shape: e.1.shape.rotated(rotation).into_owned(),
+                    physics_collider: e.1.physics_collider,
+                    collision_layers: e.1.collision_layers,
+                    collision_mask: e.1.collision_mask,
+                };
+                let bounding_box = collider.get_bounding_box(&e.0 .0);
+                let body = match &phys {
+                    // only entities with a rotation can be spun by contacts
+                    Some(phys) => solver::Body::new(e.0 .0, phys).with_rotation(
+                        e.0 .0 + e.1.shape.get_center(),
+                        e.6.map_or(0f32, |_| phys.inverse_inertia(e.1)),
+                    ),
+                    None => solver::Body::fixed(e.0 .0, physics::Material::default()),
+                };
+                old_data[id] = Some((e.0 .0, phys, collider, bounding_box, body));
             }
 
-            entity_data.into_par_iter().for_each(|ref mut p| {
+            // pairs the layer matrix rules out either way are dropped before the narrow phase
+            // sorted so the solver sees the contacts in the same order every frame
+            let mut pairs = broad_phase.pairs(&|a, b| {
+                layer_matrix.get_mask(a).intersects(b) || layer_matrix.get_mask(b).intersects(a)
+            });
+            pairs.sort_unstable();
+
+            // the narrow phase runs in parallel on every pair, the solver runs after on all contacts
+            let found: Vec<_> = pairs
+                .par_iter()
+                .filter_map(|&(first_id, second_id)| {
+                    let first = old_data.get(first_id as usize)?.as_ref()?;
+                    let second = old_data.get(second_id as usize)?.as_ref()?;
+                    // the broad phase can have grown the boxes
+                    if !first.3.is_colliding(&second.3) {
+                        return None;
+                    }
+                    let first_sees = get_query_mask(&first.2, &layer_matrix)
+                        .intersects(&second.2.collision_layers);
+                    let second_sees = get_query_mask(&second.2, &layer_matrix)
+                        .intersects(&first.2.collision_layers);
+                    if !first_sees && !second_sees {
+                        return None;
+                    }
+                    // two bodies that can't move don't need a contact
+                    let solve = first.2.physics_collider
+                        && second.2.physics_collider
+                        && (is_dynamic(&first.1) || is_dynamic(&second.1));
+                    let overlap_vec = first.2.get_collision(&first.0, &second.0, &second.2);
+                    let point = overlap_vec
+                        .filter(|_| solve)
+                        .map(|v| first.2.get_contact_point(&first.0, &second.0, &second.2, v));
+                    Some((
+                        (first_id, second_id),
+                        first_sees,
+                        second_sees,
+                        overlap_vec,
+                        point,
+                    ))
+                })
+                .collect();
+
+            let walls: Vec<_> = entity_data
+                .par_iter()
+                .map(|p| {
+                    let (_, old_phys, _, bounding_box, _) =
+                        old_data[p.3.id() as usize].as_ref().unwrap();
+                    if p.1.physics_collider && is_dynamic(old_phys) {
+                        get_wall_overlaps(bounding_box, &size)
+                    } else {
+                        Vec::new()
+                    }
+                })
+                .collect();
+
+            let mut bodies = Vec::new();
+            let mut body_index = vec![None; old_data.len()];
+            for (id, old) in old_data.iter().enumerate() {
+                if let Some((_, _, _, _, body)) = old {
+                    body_index[id] = Some(bodies.len());
+                    bodies.push(body.clone());
+                }
+            }
+            let wall_index = bodies.len();
+            bodies.push(solver::Body::fixed(
+                Vector2::new(0f32, 0f32),
+                config.wall_material,
+            ));
+
+            // every entity gets the entities in its mask that its bounding box touches
+            let mut collisions = vec![Vec::new(); old_data.len()];
+            let mut contacts = Vec::new();
+            let mut touching = HashMap::new();
+            for (pair, first_sees, second_sees, overlap_vec, point) in &found {
+                if *first_sees {
+                    collisions[pair.0 as usize].push(pair.1);
+                }
+                if *second_sees {
+                    collisions[pair.1 as usize].push(pair.0);
+                }
+                let overlap_vec = match overlap_vec {
+                    Some(overlap_vec) => *overlap_vec,
+                    None => continue,
+                };
+
+                let depth = overlap_vec.length();
+                let collision = events::CollisionData {
+                    entities: (data.4.entity(pair.0), data.4.entity(pair.1)),
+                    normal: if depth > 0f32 {
+                        overlap_vec / depth
+                    } else {
+                        Vector2::new(0f32, 0f32)
+                    },
+                    depth,
+                };
+                collision_events.single_write(if self.touching.contains_key(&collision.entities) {
+                    events::CollisionEvent::CollisionPersisted(collision)
+                } else {
+                    events::CollisionEvent::CollisionStarted(collision)
+                });
+                touching.insert(collision.entities, collision);
+
+                if let Some(point) = point {
+                    let indices = (
+                        body_index[pair.0 as usize].unwrap(),
+                        body_index[pair.1 as usize].unwrap(),
+                    );
+                    contacts.extend(solver::Contact::new(*pair, indices, overlap_vec, *point));
+                }
+            }
+            // walls use the ids at the very end of the range so they never match an entity
+            for (p, walls) in entity_data.iter().zip(&walls) {
                 let first_id = p.3.id();
-                let old = old_data[first_id as usize].as_ref().unwrap().0;
-                let collisions: Vec<_> = bvh
-                    .query_rect(&p.1.get_bounding_box(&old), &p.1.collision_mask)
-                    .iter()
-                    .filter(|id| **id != first_id)
-                    .copied()
-                    .collect();
-                for p2_id in &collisions {
-                    let p2 = old_data[*p2_id as usize].as_ref().unwrap();
-                    let overlap_vec = p.1.get_collision(&old, &p2.0, &p2.2);
-                    if let Some(unwraped) = overlap_vec {
-                        // this ensures collisions are not handled twice, but is does result in calculating it twice
-                        if p.1.physics_collider && p2.2.physics_collider {
-                            if let Some(ref mut phys) = p.2 {
-                                if let Some(p2_phys) = &p2.1 {
-                                    phys.resolve_collision_single(
-                                        &mut p.0 .0,
-                                        &p2.0,
-                                        p2_phys,
-                                        unwraped,
-                                    );
-                                }
-                            }
+                let (pos, _, collider, _, _) = old_data[first_id as usize].as_ref().unwrap();
+                for (wall, overlap_vec) in walls {
+                    let indices = (body_index[first_id as usize].unwrap(), wall_index);
+                    let (wall_pos, wall_shape) = get_wall(*wall, &size);
+                    let point =
+                        collider
+                            .shape
+                            .get_contact_point(pos, &wall_pos, &wall_shape, *overlap_vec);
+                    contacts.extend(solver::Contact::new(
+                        (first_id, u32::MAX - wall),
+                        indices,
+                        *overlap_vec,
+                        point,
+                    ));
+                }
+            }
+
+            for (pair, collision) in &self.touching {
+                if !touching.contains_key(pair) {
+                    collision_events
+                        .single_write(events::CollisionEvent::CollisionEnded(*collision));
+                }
+            }
+            self.touching = touching;
+
+            solver::solve(&mut bodies, &mut contacts, &mut cache, &config);
+
+            for p in entity_data.iter_mut() {
+                let id = p.3.id() as usize;
+                if let (Some(index), Some(phys), None) = (body_index[id], &mut p.2, p.5) {
+                    if phys.body_type == physics::BodyType::Dynamic {
+                        p.0 .0 = bodies[index].position;
+                        phys.velocity = bodies[index].velocity;
+                        phys.angular_velocity = bodies[index].angular_velocity;
+                    }
+                }
+                *p.4 = utils::Collisions(std::mem::take(&mut collisions[id]));
+            }
+        }
+    }
+}
+
+/// Runs the callbacks of sensors when something starts or stops touching them.
+#[derive(Default)]
+struct RunSensors {
+    reader: Option<ReaderId<events::CollisionEvent>>,
+}
+
+impl<'a> System<'a> for RunSensors {
+    type SystemData = (
+        Read<'a, events::EventChannel<events::CollisionEvent>>,
+        ReadStorage<'a, sensor::Sensor>,
+        Read<'a, LazyUpdate>,
+    );
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        self.reader = Some(
+            world
+                .fetch_mut::<events::EventChannel<events::CollisionEvent>>()
+                .register_reader(),
+        );
+    }
+
+    fn run(&mut self, (collision_events, sensors, lazy): Self::SystemData) {
+        let reader = self.reader.as_mut().expect("the dispatcher was not set up");
+        for event in collision_events.read(reader) {
+            let (first, second) = event.data().entities;
+            // both entities can be sensors
+            for (sensor, other) in &[(first, second), (second, first)] {
+                if let Some(s) = sensors.get(*sensor) {
+                    match event {
+                        events::CollisionEvent::CollisionStarted(_) => {
+                            s.enter(*sensor, *other, &lazy)
                         }
+                        events::CollisionEvent::CollisionEnded(_) => s.exit(*sensor, *other, &lazy),
+                        events::CollisionEvent::CollisionPersisted(_) => (),
                     }
                 }
-                *p.4 = utils::Collisions(collisions);
-            });
+            }
         }
     }
 }
 
+/// Get the layers a collider looks for other colliders on.
+fn get_query_mask(
+    col: &collider::Collider,
+    layer_matrix: &collider::LayerMatrix,
+) -> collider::LayerMask {
+    col.collision_mask & layer_matrix.get_mask(&col.collision_layers)
+}
+
+/// Check if an entity is moved by collisions, entities without physics never are.
+fn is_dynamic(phys: &Option<physics::Physics>) -> bool {
+    matches!(phys, Some(phys) if phys.body_type == physics::BodyType::Dynamic)
+}
+
+/// Get how far a bounding box is past each wall, pointing into the wall.
+fn get_wall_overlaps(bounding_box: &collider::AABB, size: &[i32; 2]) -> Vec<(u32, Vector2)> {
+    let mut overlaps = Vec::new();
+    if bounding_box.lx < 0f32 {
+        overlaps.push((0, Vector2::new(bounding_box.lx, 0f32)));
+    }
+    if bounding_box.ly < 0f32 {
+        overlaps.push((1, Vector2::new(0f32, bounding_box.ly)));
+    }
+    if bounding_box.rx > size[0] as f32 {
+        overlaps.push((2, Vector2::new(bounding_box.rx - size[0] as f32, 0f32)));
+    }
+    if bounding_box.ry > size[1] as f32 {
+        overlaps.push((3, Vector2::new(0f32, bounding_box.ry - size[1] as f32)));
+    }
+    overlaps
+}
+
+/// Get the position and shape of a wall, as a rectangle outside of the world as thick as the world is big.
+fn get_wall(wall: u32, size: &[i32; 2]) -> (Vector2, collider::Shape) {
+    let (width, height) = (size[0] as f32, size[1] as f32);
+    let thickness = width.max(height);
+    let (pos, size) = match wall {
+        0 => (
+            Vector2::new(-thickness, -thickness),
+            Vector2::new(thickness, height + 2f32 * thickness),
+        ),
+        1 => (
+            Vector2::new(-thickness, -thickness),
+            Vector2::new(width + 2f32 * thickness, thickness),
+        ),
+        2 => (
+            Vector2::new(width, -thickness),
+            Vector2::new(thickness, height + 2f32 * thickness),
+        ),
+        _ => (
+            Vector2::new(-thickness, height),
+            Vector2::new(width + 2f32 * thickness, thickness),
+        ),
+    };
+    (pos, collider::Shape::RectangeCollider { size })
+}
+
 /// Builds the world
+#[deprecated(note = "use `EngineBuilder::new`")]
 pub fn build<'a, 'b>() -> (RaylibHandle, RaylibThread, World, DispatcherBuilder<'a, 'b>) {
-    let (rl, thread) = raylib::init()
-        .resizable()
-        .size(WINDOW_SIZE[0], WINDOW_SIZE[1])
-        .title("Iron Oxide Engine")
-        .build();
-
-    let _time_since_bvh_update = 0f32;
-    let bvh_tree: Option<bvh::BVHTree> = None;
-
-    let mut world = World::new();
-    world.register::<utils::Position>();
-    world.register::<utils::Collisions>();
-    world.register::<physics::Physics>();
-    world.register::<collider::Collider>();
-    world.register::<renderer::Renderer>();
-    world.register::<utils::Frozen>();
-    world.insert(utils::Delta(0.00));
-    world.insert([rl.get_screen_width(), rl.get_screen_height()]);
-    world.insert(bvh_tree);
-    let dispatcher = DispatcherBuilder::new()
-        .with(UpdatePhysics, "update_physics", &[])
-        .with(CollideBounds, "collide_bounds", &["update_physics"])
-        .with(CollideEnities, "collide_entities", &["update_physics"])
-        .with(ShrinkBvh, "shrink_bvh", &[]);
-    (rl, thread, world, dispatcher)
+    EngineBuilder::new().into_parts()
+}
+
+/// Builds the world without opening a window, useful for tests and servers
+#[deprecated(note = "use `EngineBuilder::headless`")]
+pub fn build_headless<'a, 'b>() -> (World, DispatcherBuilder<'a, 'b>) {
+    EngineBuilder::headless().into_headless_parts()
 }
 
-/// Finalizes the world, run this after adding custom systems
+/// Finalizes the world, run this after adding custom systems.
+/// The last value of the tuple is unused, it used to time bvh rebuilds.
+#[deprecated(note = "use `EngineBuilder::build`")]
 pub fn init<'a, 'b>(
-    state: (RaylibHandle, RaylibThread, World, DispatcherBuilder<'a, 'b>),
+    mut state: (RaylibHandle, RaylibThread, World, DispatcherBuilder<'a, 'b>),
 ) -> (
     raylib::RaylibHandle,
     raylib::RaylibThread,
@@ -232,14 +562,26 @@ pub fn init<'a, 'b>(
     Dispatcher<'a, 'b>,
     f32,
 ) {
-    let dispatcher = state.3.build();
+    let mut dispatcher = state.3.build();
+    dispatcher.setup(&mut state.2);
 
-    let time_since_bvh_update = 0f32;
+    (state.0, state.1, state.2, dispatcher, 0f32)
+}
+
+/// Finalizes a headless world, run this after adding custom systems.
+/// The last value of the tuple is unused, like in `init`.
+#[deprecated(note = "use `EngineBuilder::build`")]
+pub fn init_headless<'a, 'b>(
+    mut state: (World, DispatcherBuilder<'a, 'b>),
+) -> (World, Dispatcher<'a, 'b>, f32) {
+    let mut dispatcher = state.1.build();
+    dispatcher.setup(&mut state.0);
 
-    (state.0, state.1, state.2, dispatcher, time_since_bvh_update)
+    (state.0, dispatcher, 0f32)
 }
 
 /// Run this every frame
+#[deprecated(note = "use `Engine::update`")]
 pub fn update<'a, 'b>(
     state: &mut (
         raylib::RaylibHandle,
@@ -250,74 +592,234 @@ pub fn update<'a, 'b>(
     ),
     callback: fn(&mut World, &mut raylib::prelude::RaylibDrawHandle),
 ) {
-    let (rl, thread, world, dispatcher, time_since_bvh_update) = state;
-
-    // update screen size
-    if rl.is_window_resized() {
-        let mut size = world.write_resource::<[i32; 2]>();
-        *size = [rl.get_screen_width(), rl.get_screen_height()]
-    }
+    let (rl, thread, world, dispatcher, _) = state;
+    engine::update_frame(rl, thread, world, dispatcher, callback);
+}
 
-    world.maintain();
+/// Advance a headless world by a fixed amount of time
+#[deprecated(note = "use `Engine::step`")]
+pub fn step<'a, 'b>(state: &mut (World, Dispatcher<'a, 'b>, f32), dt: f32) {
+    let (world, dispatcher, _) = state;
+    engine::step_frame(world, dispatcher, dt);
+}
 
-    // update bvh
-    // this must happen after maintain (so that deletes get processed) and before updates (so that no new deletes are queued)
+/// Bring the broad phase up to date with entities that were created, moved or deleted without going through `utils`.
+/// Colliders that aren't in the broad phase or changed their layers are inserted, colliders whose bounding box changed
+/// (moved by scripts, frozen, without physics, or a new entity with a reused id) are updated, and dead entities are removed.
+pub fn sync_broad_phase(broad_phase: &mut dyn broad_phase::BroadPhase, entities: &BvhData) {
+    let mut colliders = 0;
+    for (ent, pos, col, rot) in
+        (&entities.0, &entities.1, &entities.2, (&entities.3).maybe()).join()
     {
-        let bvh_data: BvhData = world.system_data();
-        let mut bvh_write: Write<Option<bvh::BVHTree>> = world.system_data();
-        if *time_since_bvh_update > MIN_BHV_UPDATE_TIME || bvh_write.is_none() {
-            *bvh_write = create_bvh(bvh_data);
-            *time_since_bvh_update = 0f32;
+        colliders += 1;
+        let rotation = rot.map_or(0f32, |r| r.0);
+        let bounding_box = col.get_bounding_box_rotated(&pos.0, rotation);
+        if broad_phase.get_layers(ent.id()) != Some(col.collision_layers) {
+            broad_phase.insert(&(col, pos.0, bounding_box, ent.id()));
+        } else if broad_phase.get_bounding_box(ent.id()).as_ref() != Some(&bounding_box) {
+            // it isn't known how far it moved, so the box isn't stretched
+            broad_phase.update(ent.id(), &bounding_box, Vector2::new(0f32, 0f32));
         }
     }
-
-    dispatcher.dispatch(world);
-
-    // draw everything
-    {
-        let mut d = rl.begin_drawing(thread);
-        d.clear_background(Color::WHITE);
-
-        {
-            let mut system_data: RenderingData = world.system_data();
-            for data in (
-                &mut system_data.0,
-                &system_data.1,
-                (&system_data.2).maybe(),
-                (&system_data.3).maybe(),
-            )
-                .join()
-            {
-                let (r, pos, _phys, _col) = data;
-                r.render(&mut d, pos);
-            }
+    // every collider is in the broad phase now, so if the counts match nothing needs to be removed
+    if broad_phase.len() == colliders {
+        return;
+    }
+    for id in broad_phase.get_ids() {
+        let entity = entities.0.entity(id);
+        if !entities.0.is_alive(entity) || entities.2.get(entity).is_none() {
+            broad_phase.remove(id);
         }
-        callback(world, &mut d);
+    }
+}
+
+/// Construct a new bvh tree
+pub fn create_bvh(entities: BvhData) -> Option<bvh::BVHTree> {
+    create_bvh_with(entities, bvh::BuildStrategy::Median)
+}
 
-        d.draw_fps(0, 0);
+/// Construct a new bvh tree with the chosen way of splitting the nodes
+pub fn create_bvh_with(entities: BvhData, strategy: bvh::BuildStrategy) -> Option<bvh::BVHTree> {
+    let data = get_entity_data(&entities);
+    if data.len() > 0 {
+        Some(bvh::BVHTree::with_strategy(data, strategy))
+    } else {
+        None
     }
+}
 
-    // update the delta time
-    {
-        let mut delta = world.write_resource::<utils::Delta>();
-        *delta = utils::Delta(rl.get_frame_time());
-        *time_since_bvh_update += delta.0;
+/// Construct the broad phase chosen in the config
+pub fn create_broad_phase(
+    entities: BvhData,
+    config: &PhysicsConfig,
+) -> Option<broad_phase::DynBroadPhase> {
+    let data = get_entity_data(&entities);
+    if data.is_empty() {
+        return None;
     }
+    Some(match config.broad_phase {
+        broad_phase::BroadPhaseKind::Bvh => {
+            Box::new(bvh::BVHTree::with_strategy(data, config.bvh_build_strategy))
+        }
+        broad_phase::BroadPhaseKind::Grid => {
+            Box::new(broad_phase::SpatialHash::new(config.grid_cell_size, data))
+        }
+        broad_phase::BroadPhaseKind::SweepAndPrune => {
+            Box::new(broad_phase::SweepAndPrune::new(data))
+        }
+    })
 }
 
-/// Construct a new bvh tree
-pub fn create_bvh(entities: BvhData) -> Option<bvh::BVHTree> {
+/// Get the collider, position, bounding box and id of every entity with a collider
+fn get_entity_data<'a>(entities: &'a BvhData) -> Vec<broad_phase::EntityData<'a>> {
     let mut data = Vec::new();
 
-    for entity in (&entities.0, &entities.1, &entities.2).join() {
-        let (ent, pos, col) = entity;
+    for entity in (&entities.0, &entities.1, &entities.2, (&entities.3).maybe()).join() {
+        let (ent, pos, col, rot) = entity;
         let id = ent.id();
-        data.push((col, pos.0, col.get_bounding_box(&pos.0), id));
+        let rotation = rot.map_or(0f32, |r| r.0);
+        data.push((
+            col,
+            pos.0,
+            col.get_bounding_box_rotated(&pos.0, rotation),
+            id,
+        ));
     }
 
-    if data.len() > 0 {
-        Some(bvh::BVHTree::new(data))
-    } else {
-        None
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use events::{CollisionEvent, EventChannel};
+
+    fn add_rect(engine: &mut Engine, pos: Vector2, physics: Option<physics::Physics>) -> Entity {
+        let collider = collider::Collider {
+            shape: collider::Shape::RectangeCollider {
+                size: Vector2::new(10f32, 10f32),
+            },
+            physics_collider: false,
+            collision_layers: collider::LayerMask::layer(0),
+            collision_mask: collider::LayerMask::layer(0),
+        };
+        let builder = engine
+            .world_mut()
+            .create_entity()
+            .with(utils::Position(pos))
+            .with(collider)
+            .with(utils::Collisions(Vec::new()));
+        match physics {
+            Some(physics) => builder.with(physics).build(),
+            None => builder.build(),
+        }
+    }
+
+    fn read_events(engine: &Engine, reader: &mut ReaderId<CollisionEvent>) -> Vec<CollisionEvent> {
+        engine
+            .world()
+            .fetch::<EventChannel<CollisionEvent>>()
+            .read(reader)
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn collisions_start_persist_and_end() {
+        let mut engine = EngineBuilder::headless().build();
+        let mut reader = engine
+            .world_mut()
+            .fetch_mut::<EventChannel<CollisionEvent>>()
+            .register_reader();
+        let still = add_rect(&mut engine, Vector2::new(100f32, 100f32), None);
+        let mut physics = physics::Physics::new(1f32);
+        physics.velocity = Vector2::new(100f32, 0f32);
+        let moving = add_rect(&mut engine, Vector2::new(85f32, 100f32), Some(physics));
+        let entities = (still.min(moving), still.max(moving));
+
+        let mut kinds = Vec::new();
+        for _ in 0..3 {
+            engine.step(0.1f32);
+            let events = read_events(&engine, &mut reader);
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].data().entities, entities);
+            kinds.push(match events[0] {
+                CollisionEvent::CollisionStarted(_) => "started",
+                CollisionEvent::CollisionPersisted(_) => "persisted",
+                CollisionEvent::CollisionEnded(_) => "ended",
+            });
+        }
+        assert_eq!(kinds, ["started", "persisted", "ended"]);
+
+        engine.step(0.1f32);
+        assert!(read_events(&engine, &mut reader).is_empty());
+    }
+
+    #[test]
+    fn moved_colliders_are_synced_to_the_broad_phase() {
+        let mut engine = EngineBuilder::headless().build();
+        let moved = add_rect(&mut engine, Vector2::new(100f32, 100f32), None);
+        let frozen = add_rect(&mut engine, Vector2::new(200f32, 100f32), None);
+        engine
+            .world_mut()
+            .write_storage()
+            .insert(frozen, utils::Frozen)
+            .unwrap();
+        engine.step(0.1f32);
+
+        let query = |engine: &Engine, x: f32| {
+            let broad_phase = engine
+                .world()
+                .read_resource::<Option<broad_phase::DynBroadPhase>>();
+            broad_phase
+                .as_ref()
+                .unwrap()
+                .query_point(&Vector2::new(x, 105f32), &collider::LayerMask::ALL)
+        };
+        {
+            let mut positions = engine.world_mut().write_storage::<utils::Position>();
+            positions.get_mut(moved).unwrap().0.x = 300f32;
+            positions.get_mut(frozen).unwrap().0.x = 400f32;
+        }
+        engine.step(0.1f32);
+        assert!(query(&engine, 105f32).is_empty());
+        assert!(query(&engine, 205f32).is_empty());
+        assert_eq!(query(&engine, 305f32), vec![moved.id()]);
+        assert_eq!(query(&engine, 405f32), vec![frozen.id()]);
+
+        // a new entity with the id of a deleted one is found where the new one is
+        engine.world_mut().delete_entity(moved).unwrap();
+        engine.world_mut().maintain();
+        let new = add_rect(&mut engine, Vector2::new(500f32, 100f32), None);
+        assert_eq!(new.id(), moved.id());
+        engine.step(0.1f32);
+        assert!(query(&engine, 305f32).is_empty());
+        assert_eq!(query(&engine, 505f32), vec![new.id()]);
+    }
+
+    #[test]
+    fn reused_ids_are_new_collisions() {
+        let mut engine = EngineBuilder::headless().build();
+        let mut reader = engine
+            .world_mut()
+            .fetch_mut::<EventChannel<CollisionEvent>>()
+            .register_reader();
+        let first = add_rect(&mut engine, Vector2::new(100f32, 100f32), None);
+        let old = add_rect(&mut engine, Vector2::new(105f32, 100f32), None);
+        engine.step(0.1f32);
+        read_events(&engine, &mut reader);
+
+        engine.world_mut().delete_entity(old).unwrap();
+        engine.world_mut().maintain();
+        let new = add_rect(&mut engine, Vector2::new(105f32, 100f32), None);
+        assert_eq!(new.id(), old.id());
+        engine.step(0.1f32);
+
+        let events = read_events(&engine, &mut reader);
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().any(|e| matches!(e,
+            CollisionEvent::CollisionStarted(data) if data.entities == (first, new))));
+        assert!(events.iter().any(|e| matches!(e,
+            CollisionEvent::CollisionEnded(data) if data.entities == (first, old))));
     }
 }
@@ -1,5 +1,5 @@
-use crate::COLLISION_FRICTION;
-use crate::FRICTION;
+use crate::collider::Collider;
+use crate::config::PhysicsConfig;
 use raylib::core::math::Vector2;
 use specs::{Component, VecStorage};
 
@@ -8,7 +8,14 @@ use specs::{Component, VecStorage};
 #[storage(VecStorage)]
 pub struct Physics {
     pub velocity: Vector2,
+    /// Rotation speed in radians per second.
+    pub angular_velocity: f32,
+    pub material: Material,
+    pub body_type: BodyType,
+    /// Sweep the collider along its path every frame so it can't tunnel through thin colliders when moving fast.
+    pub ccd: bool,
     mass: f32,
+    inertia: Option<f32>,
 }
 
 impl Physics {
@@ -16,142 +23,167 @@ impl Physics {
     pub fn new(mass: f32) -> Physics {
         Physics {
             velocity: Vector2::new(0f32, 0f32),
+            angular_velocity: 0f32,
+            material: Material::default(),
+            body_type: BodyType::Dynamic,
+            ccd: false,
             mass,
+            inertia: None,
         }
     }
 
-    /// Update a position with the physics system.
-    pub fn update(&mut self, pos: &mut Vector2, delta: f32) {
-        *pos += self.velocity * delta;
-        self.velocity *= f32::powf(FRICTION, delta);
-    }
-
-    /// Resolve a collision between two entities
-    pub fn resolve_collision(
-        &mut self,
-        pos: &mut Vector2,
-        other_pos: &mut Vector2,
-        other_physics: &mut Physics,
-        overlap_vec: Vector2,
-    ) {
-        if overlap_vec.x == 0f32 || overlap_vec.y == 0f32 {
-            return self.resolve_collision_aabb(pos, other_pos, other_physics, overlap_vec);
-        }
+    /// Sets the moment of inertia, by default it is calculated from the shape of the collider.
+    pub fn with_inertia(mut self, inertia: f32) -> Physics {
+        self.inertia = Some(inertia);
+        self
+    }
 
-        let diff = *pos - *other_pos;
+    /// Sets the material used when colliding.
+    pub fn with_material(mut self, material: Material) -> Physics {
+        self.material = material;
+        self
+    }
 
-        let normed = diff.normalized();
-        let normed_sq = normed * normed;
-        let div = normed_sq / diff;
+    /// Sets how the body is moved.
+    pub fn with_body_type(mut self, body_type: BodyType) -> Physics {
+        self.body_type = body_type;
+        self
+    }
 
-        // if the distence is zero, the vector will be NaN
-        // not sure if this is the best way to handle this, but it works
-        if div.x.is_nan() || div.y.is_nan() {
-            return;
-        }
+    /// Sets if continuous collision detection is used.
+    pub fn with_ccd(mut self, ccd: bool) -> Physics {
+        self.ccd = ccd;
+        self
+    }
+
+    /// Get the mass.
+    pub fn mass(&self) -> f32 {
+        self.mass
+    }
 
-        let dot_prod = (self.velocity - other_physics.velocity).dot(diff);
-        let new_vel = div * dot_prod;
-
-        *pos -= overlap_vec / 2f32;
-        let m = (2f32 * other_physics.mass) / (other_physics.mass + self.mass);
-        let force = new_vel * m * COLLISION_FRICTION;
-        self.velocity -= force;
-
-        *other_pos += overlap_vec / 2f32;
-        let m = (2f32 * self.mass) / (self.mass + other_physics.mass);
-        let force = new_vel * m * COLLISION_FRICTION;
-        other_physics.velocity += force;
-    }
-
-    /// Resolve a collision that happens between two axis aligned rectangles.
-    fn resolve_collision_aabb(
-        &mut self,
-        pos: &mut Vector2,
-        other_pos: &mut Vector2,
-        other_physics: &mut Physics,
-        overlap_vec: Vector2,
-    ) {
-        *pos -= overlap_vec / 2f32;
-        *other_pos += overlap_vec / 2f32;
-        let m1 = (2f32 * self.mass) / (self.mass + other_physics.mass);
-        let m2 = (2f32 * other_physics.mass) / (self.mass + other_physics.mass);
-        if overlap_vec.x == 0f32 {
-            let other_vel = other_physics.velocity.y;
-            other_physics.velocity.y = self.velocity.y * m2 * COLLISION_FRICTION;
-            self.velocity.y = other_vel * m1 * COLLISION_FRICTION;
+    /// Get the moment of inertia, calculated from the collider's shape if it wasn't set.
+    pub fn inertia(&self, collider: &Collider) -> f32 {
+        self.inertia
+            .unwrap_or_else(|| collider.get_inertia(self.mass))
+    }
+
+    /// Get one over the mass, static and kinematic bodies have infinite mass so this is zero.
+    pub fn inverse_mass(&self) -> f32 {
+        if self.body_type == BodyType::Dynamic && self.mass > 0f32 {
+            1f32 / self.mass
         } else {
-            let other_vel = other_physics.velocity.x;
-            other_physics.velocity.x = self.velocity.x * m2 * COLLISION_FRICTION;
-            self.velocity.x = other_vel * m1 * COLLISION_FRICTION;
+            0f32
         }
     }
 
-    /// Resolve a collision by only moving one entity.
-    pub fn resolve_collision_single(
-        &mut self,
-        pos: &mut Vector2,
-        other_pos: &Vector2,
-        other_physics: &Physics,
-        overlap_vec: Vector2,
-    ) {
-        if overlap_vec.x == 0f32 || overlap_vec.y == 0f32 {
-            return self.resolve_collision_simple_aabb(pos, other_pos, other_physics, overlap_vec);
+    /// Get one over the moment of inertia, bodies that aren't dynamic can't be rotated by contacts so this is zero.
+    pub fn inverse_inertia(&self, collider: &Collider) -> f32 {
+        let inertia = self.inertia(collider);
+        if self.body_type == BodyType::Dynamic && inertia > 0f32 {
+            1f32 / inertia
+        } else {
+            0f32
         }
+    }
 
-        let dif = *pos - *other_pos;
-
-        let normed = dif.normalized();
-        let normed_sq = normed * normed;
-        let div = normed_sq / dif;
-
-        // not sure if this is the best way to handle this, but it works
-        if div.x.is_nan() || div.y.is_nan() {
+    /// Update a position with the physics system.
+    pub fn update(&mut self, pos: &mut Vector2, delta: f32, config: &PhysicsConfig) {
+        if self.body_type == BodyType::Static {
             return;
         }
-
-        let dot_prod = (self.velocity - other_physics.velocity).dot(dif);
-        let new_vel = div * dot_prod;
-
-        *pos -= overlap_vec / 2f32;
-        let m = (2f32 * other_physics.mass) / (other_physics.mass + self.mass);
-        let force = new_vel * m * COLLISION_FRICTION;
-        self.velocity -= force;
-    }
-
-    /// Resolve a collision that happens between two axis aligned rectangles by only moving one entity.
-    fn resolve_collision_simple_aabb(
-        &mut self,
-        pos: &mut Vector2,
-        _other_pos: &Vector2,
-        other_physics: &Physics,
-        overlap_vec: Vector2,
-    ) {
-        *pos -= overlap_vec / 2f32;
-        let m1 = (2f32 * self.mass) / (self.mass + other_physics.mass);
-        if overlap_vec.x == 0f32 {
-            let other_vel = other_physics.velocity.y;
-            self.velocity.y = other_vel * m1 * COLLISION_FRICTION;
-        } else {
-            let other_vel = other_physics.velocity.x;
-            self.velocity.x = other_vel * m1 * COLLISION_FRICTION;
+        *pos += self.velocity * delta;
+        // kinematic bodies keep the velocity their script gave them
+        if self.body_type == BodyType::Dynamic {
+            self.velocity *= f32::powf(config.friction, delta);
         }
     }
 
-    /// Resolve a collision with a wall.
-    pub fn collide_bound(&mut self, position: &mut Vector2, collision_vec: Vector2) {
-        if collision_vec.x != 0f32 {
-            position.x += collision_vec.x;
-            self.velocity.x = -self.velocity.x;
-        } else {
-            position.y += collision_vec.y;
-            self.velocity.y = -self.velocity.y;
+    /// Update a rotation with the physics system.
+    pub fn update_rotation(&mut self, rotation: &mut f32, delta: f32, config: &PhysicsConfig) {
+        if self.body_type == BodyType::Static {
+            return;
+        }
+        *rotation += self.angular_velocity * delta;
+        if self.body_type == BodyType::Dynamic {
+            self.angular_velocity *= f32::powf(config.friction, delta);
         }
     }
 }
 
 impl PartialEq for Physics {
     fn eq(&self, other: &Self) -> bool {
-        self.mass == other.mass && self.velocity == other.velocity
+        self.mass == other.mass
+            && self.body_type == other.body_type
+            && self.ccd == other.ccd
+            && self.inertia == other.inertia
+            && self.velocity == other.velocity
+            && self.angular_velocity == other.angular_velocity
+            && self.material == other.material
+    }
+}
+
+/// How bouncy and rough the surface of a body is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Material {
+    /// How much of the speed is kept after bouncing, 0 does not bounce and 1 is perfectly elastic.
+    pub restitution: f32,
+    /// Friction that stops a body that is sliding slowly.
+    pub static_friction: f32,
+    /// Friction that slows down a body that is sliding.
+    pub dynamic_friction: f32,
+}
+
+impl Material {
+    /// Creates a new material.
+    pub fn new(restitution: f32, static_friction: f32, dynamic_friction: f32) -> Material {
+        Material {
+            restitution,
+            static_friction,
+            dynamic_friction,
+        }
+    }
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Material::new(1f32, 0f32, 0f32)
+    }
+}
+
+/// How a body is moved by the physics system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyType {
+    /// Moved by its velocity, gravity and collisions.
+    Dynamic,
+    /// Never moves and has infinite mass, for walls and platforms.
+    Static,
+    /// Moved only by its velocity, which is set by scripts, and pushes dynamic bodies with infinite mass.
+    Kinematic,
+}
+
+impl Default for BodyType {
+    fn default() -> Self {
+        BodyType::Dynamic
+    }
+}
+
+/// How the values of two materials are combined when they collide.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CombineRule {
+    Min,
+    Max,
+    Average,
+    Multiply,
+}
+
+impl CombineRule {
+    /// Combine two values with this rule.
+    pub fn combine(&self, a: f32, b: f32) -> f32 {
+        match self {
+            CombineRule::Min => a.min(b),
+            CombineRule::Max => a.max(b),
+            CombineRule::Average => (a + b) / 2f32,
+            CombineRule::Multiply => a * b,
+        }
     }
 }
@@ -1,3 +1,4 @@
+use crate::utils;
 use crate::utils::Position;
 use raylib::prelude::*;
 
@@ -20,6 +21,10 @@ pub enum Renderer {
         scale: f32,
         tint: Color,
     },
+    PolygonRenderer {
+        vertices: Vec<Vector2>,
+        color: Color,
+    },
 }
 
 impl Renderer {
@@ -40,17 +45,79 @@ impl Renderer {
         }
     }
 
+    /// create a polygon renderer, panics if it has less than three vertices
+    pub fn polygon(vertices: Vec<Vector2>, color: Color) -> Renderer {
+        assert!(
+            vertices.len() >= 3,
+            "polygons must have at least three vertices"
+        );
+        Renderer::PolygonRenderer { vertices, color }
+    }
+
     /// Render the entity at a location.
     pub fn render(&self, d: &mut impl raylib::core::drawing::RaylibDraw, position: &Position) {
+        self.render_rotated(d, position, 0f32);
+    }
+
+    /// Render the entity at a location, rotated around its center by an angle in radians.
+    pub fn render_rotated(
+        &self,
+        d: &mut impl raylib::core::drawing::RaylibDraw,
+        position: &Position,
+        rotation: f32,
+    ) {
         match self {
             Renderer::CircleRenderer { radius, color } => {
                 d.draw_circle_v(position.0 + (Vector2::one() * (*radius)), *radius, color);
             }
             Renderer::RectangeRenderer { size, color } => {
-                d.draw_rectangle_v(position.0, size, color);
+                let center = position.0 + *size / 2f32;
+                d.draw_rectangle_pro(
+                    Rectangle::new(center.x, center.y, size.x, size.y),
+                    *size / 2f32,
+                    rotation.to_degrees(),
+                    color,
+                );
             }
             Renderer::SpriteRenderer { img, scale, tint } => {
-                d.draw_texture_ex(img, position.0, *scale, 0.0, tint)
+                let size = Vector2::new(img.width as f32, img.height as f32);
+                let scaled = size * *scale;
+                let center = position.0 + scaled / 2f32;
+                d.draw_texture_pro(
+                    img,
+                    Rectangle::new(0f32, 0f32, size.x, size.y),
+                    Rectangle::new(center.x, center.y, scaled.x, scaled.y),
+                    scaled / 2f32,
+                    rotation.to_degrees(),
+                    tint,
+                )
+            }
+            Renderer::PolygonRenderer { vertices, color } => {
+                if vertices.len() < 3 {
+                    return;
+                }
+                // rotate around the center of the bounding box, the same way polygon colliders do
+                let mut min = vertices[0];
+                let mut max = vertices[0];
+                for v in vertices {
+                    min = Vector2::new(min.x.min(v.x), min.y.min(v.y));
+                    max = Vector2::new(max.x.max(v.x), max.y.max(v.y));
+                }
+                let center = (min + max) / 2f32;
+                let mut points: Vec<_> = vertices
+                    .iter()
+                    .map(|v| position.0 + center + utils::rotate(*v - center, rotation))
+                    .collect();
+                // raylib only draws triangles that are counter-clockwise on the screen
+                let mut area = 0f32;
+                for (i, p) in points.iter().enumerate() {
+                    let next = points[(i + 1) % points.len()];
+                    area += p.x * next.y - next.x * p.y;
+                }
+                if area > 0f32 {
+                    points.reverse();
+                }
+                d.draw_triangle_fan(&points, color);
             }
         }
     }
@@ -0,0 +1,161 @@
+use specs::{Component, Entity, HashMapStorage, LazyUpdate};
+
+/// A callback run by a sensor. It gets the sensor entity, the other entity, and the `LazyUpdate` to change the world with.
+pub type SensorCallback = Box<dyn Fn(Entity, Entity, &LazyUpdate) + Send + Sync>;
+
+/// Runs callbacks when other colliders start or stop touching the collider of this entity.
+/// Set `physics_collider` to false on the collider to detect entities without pushing them.
+#[derive(Component, Default)]
+#[storage(HashMapStorage)]
+pub struct Sensor {
+    on_enter: Option<SensorCallback>,
+    on_exit: Option<SensorCallback>,
+}
+
+impl Sensor {
+    /// Creates a sensor without any callbacks.
+    pub fn new() -> Sensor {
+        Sensor::default()
+    }
+
+    /// Sets the callback that runs when another collider enters the sensor.
+    pub fn on_enter(
+        mut self,
+        callback: impl Fn(Entity, Entity, &LazyUpdate) + Send + Sync + 'static,
+    ) -> Sensor {
+        self.on_enter = Some(Box::new(callback));
+        self
+    }
+
+    /// Sets the callback that runs when another collider leaves the sensor.
+    pub fn on_exit(
+        mut self,
+        callback: impl Fn(Entity, Entity, &LazyUpdate) + Send + Sync + 'static,
+    ) -> Sensor {
+        self.on_exit = Some(Box::new(callback));
+        self
+    }
+
+    /// Run the enter callback if there is one.
+    pub(crate) fn enter(&self, sensor: Entity, other: Entity, lazy: &LazyUpdate) {
+        if let Some(callback) = &self.on_enter {
+            callback(sensor, other, lazy);
+        }
+    }
+
+    /// Run the exit callback if there is one.
+    pub(crate) fn exit(&self, sensor: Entity, other: Entity, lazy: &LazyUpdate) {
+        if let Some(callback) = &self.on_exit {
+            callback(sensor, other, lazy);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collider::{Collider, LayerMask, Shape};
+    use crate::physics::Physics;
+    use crate::utils::{Collisions, Position};
+    use crate::{Engine, EngineBuilder};
+    use raylib::math::Vector2;
+    use specs::{Builder, WorldExt};
+    use std::sync::{Arc, Mutex};
+
+    type Calls = Arc<Mutex<Vec<(&'static str, Entity, Entity)>>>;
+
+    fn add_rect(engine: &mut Engine, x: f32, velocity: f32, sensor: Option<Sensor>) -> Entity {
+        let mut physics = Physics::new(1f32);
+        physics.velocity = Vector2::new(velocity, 0f32);
+        let builder = engine
+            .world_mut()
+            .create_entity()
+            .with(Position(Vector2::new(x, 100f32)))
+            .with(Collider {
+                shape: Shape::RectangeCollider {
+                    size: Vector2::new(10f32, 10f32),
+                },
+                physics_collider: sensor.is_none(),
+                collision_layers: LayerMask::layer(0),
+                collision_mask: LayerMask::layer(0),
+            })
+            .with(physics)
+            .with(Collisions(Vec::new()));
+        match sensor {
+            Some(sensor) => builder.with(sensor).build(),
+            None => builder.build(),
+        }
+    }
+
+    fn recording_sensor(calls: &Calls) -> Sensor {
+        let enter = calls.clone();
+        let exit = calls.clone();
+        Sensor::new()
+            .on_enter(move |sensor, other, _| enter.lock().unwrap().push(("enter", sensor, other)))
+            .on_exit(move |sensor, other, _| exit.lock().unwrap().push(("exit", sensor, other)))
+    }
+
+    #[test]
+    fn callbacks_run_when_entering_and_leaving() {
+        let calls = Calls::default();
+        let mut engine = EngineBuilder::headless().build();
+        let sensor = add_rect(&mut engine, 100f32, 0f32, Some(recording_sensor(&calls)));
+        let other = add_rect(&mut engine, 85f32, 100f32, None);
+
+        engine.step(0.1f32);
+        assert_eq!(*calls.lock().unwrap(), [("enter", sensor, other)]);
+        engine.step(0.1f32);
+        assert_eq!(calls.lock().unwrap().len(), 1);
+        engine.step(0.1f32);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [("enter", sensor, other), ("exit", sensor, other)]
+        );
+    }
+
+    #[test]
+    fn deleting_the_other_entity_exits() {
+        let calls = Calls::default();
+        let mut engine = EngineBuilder::headless().build();
+        let sensor = add_rect(&mut engine, 100f32, 0f32, Some(recording_sensor(&calls)));
+        let other = add_rect(&mut engine, 95f32, 0f32, None);
+
+        engine.step(0.1f32);
+        engine.world_mut().delete_entity(other).unwrap();
+        engine.step(0.1f32);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [("enter", sensor, other), ("exit", sensor, other)]
+        );
+    }
+
+    #[test]
+    fn enter_callbacks_can_delete_the_other_entity() {
+        let mut engine = EngineBuilder::headless().build();
+        let pickup = Sensor::new().on_enter(|_, other, lazy| {
+            lazy.exec_mut(move |world| world.delete_entity(other).unwrap())
+        });
+        add_rect(&mut engine, 100f32, 0f32, Some(pickup));
+        let other = add_rect(&mut engine, 95f32, 0f32, None);
+
+        engine.step(0.1f32);
+        engine.step(0.1f32);
+        assert!(!engine.world().is_alive(other));
+        // the exit of the deleted entity must not break the next step
+        engine.step(0.1f32);
+    }
+
+    #[test]
+    fn deleting_the_sensor_runs_no_callbacks() {
+        let calls = Calls::default();
+        let mut engine = EngineBuilder::headless().build();
+        let sensor = add_rect(&mut engine, 100f32, 0f32, Some(recording_sensor(&calls)));
+        let other = add_rect(&mut engine, 95f32, 0f32, None);
+
+        engine.step(0.1f32);
+        engine.world_mut().delete_entity(sensor).unwrap();
+        engine.step(0.1f32);
+        engine.step(0.1f32);
+        assert_eq!(*calls.lock().unwrap(), [("enter", sensor, other)]);
+    }
+}
@@ -0,0 +1,414 @@
+use crate::config::PhysicsConfig;
+use crate::physics::{BodyType, Material, Physics};
+use raylib::core::math::Vector2;
+use std::collections::HashMap;
+
+/// A body taking part in the solver.
+#[derive(Debug, Clone)]
+pub struct Body {
+    pub position: Vector2,
+    pub velocity: Vector2,
+    /// Rotation speed in radians per second.
+    pub angular_velocity: f32,
+    inverse_mass: f32,
+    /// The point the body rotates around.
+    center: Vector2,
+    /// One over the moment of inertia, zero for bodies that contacts can't rotate.
+    inverse_inertia: f32,
+    material: Material,
+    /// Position change from the position iterations of this step.
+    correction: Vector2,
+}
+
+impl Body {
+    /// Creates a body from a physics component, contacts don't rotate it until `Body::with_rotation` is used.
+    pub fn new(position: Vector2, physics: &Physics) -> Body {
+        // static bodies never move, whatever their velocity is
+        let moves = physics.body_type != BodyType::Static;
+        Body {
+            position,
+            velocity: if moves {
+                physics.velocity
+            } else {
+                Vector2::new(0f32, 0f32)
+            },
+            angular_velocity: if moves {
+                physics.angular_velocity
+            } else {
+                0f32
+            },
+            inverse_mass: physics.inverse_mass(),
+            center: position,
+            inverse_inertia: 0f32,
+            material: physics.material,
+            correction: Vector2::new(0f32, 0f32),
+        }
+    }
+
+    /// Creates a body that can't be moved, like the walls.
+    pub fn fixed(position: Vector2, material: Material) -> Body {
+        Body {
+            position,
+            velocity: Vector2::new(0f32, 0f32),
+            angular_velocity: 0f32,
+            inverse_mass: 0f32,
+            center: position,
+            inverse_inertia: 0f32,
+            material,
+            correction: Vector2::new(0f32, 0f32),
+        }
+    }
+
+    /// Lets contacts rotate the body around a point, `Physics::inverse_inertia` gets the inverse inertia.
+    pub fn with_rotation(mut self, center: Vector2, inverse_inertia: f32) -> Body {
+        self.center = center;
+        self.inverse_inertia = inverse_inertia;
+        self
+    }
+
+    /// Get the velocity of a point that is offset from the center of the body.
+    fn get_point_velocity(&self, offset: Vector2) -> Vector2 {
+        self.velocity + Vector2::new(-offset.y, offset.x) * self.angular_velocity
+    }
+
+    /// Get the inverse mass of the body for an impulse along a direction at an offset from its center.
+    fn get_inverse_mass_at(&self, offset: Vector2, direction: Vector2) -> f32 {
+        let arm = cross(offset, direction);
+        self.inverse_mass + self.inverse_inertia * arm * arm
+    }
+}
+
+/// The 2d cross product, the z of the 3d cross product of the vectors.
+fn cross(a: Vector2, b: Vector2) -> f32 {
+    a.x * b.y - a.y * b.x
+}
+
+/// A contact constraint between two bodies.
+#[derive(Debug, Clone)]
+pub struct Contact {
+    /// The entity ids of the two bodies, the first is always the smaller id.
+    pub entities: (u32, u32),
+    /// The indices of the two bodies in the solver.
+    bodies: (usize, usize),
+    /// Unit vector pointing from the first body towards the second.
+    pub normal: Vector2,
+    /// Where the bodies touch.
+    pub point: Vector2,
+    /// How far the bodies overlap along the normal.
+    pub depth: f32,
+    /// Accumulated impulse along the normal.
+    pub normal_impulse: f32,
+    /// Accumulated friction impulse along the tangent.
+    pub tangent_impulse: f32,
+    /// Mass used to push the bodies apart, rotation is ignored for this.
+    mass: f32,
+    normal_mass: f32,
+    tangent_mass: f32,
+    /// Offsets of the point from the centers of the two bodies.
+    offsets: (Vector2, Vector2),
+    velocity_bias: f32,
+    static_friction: f32,
+    dynamic_friction: f32,
+}
+
+impl Contact {
+    /// Creates a contact from an overlap vector pointing from the first body towards the second, and the point where they touch.
+    /// Returns None if the overlap has no direction.
+    pub fn new(
+        entities: (u32, u32),
+        bodies: (usize, usize),
+        overlap_vec: Vector2,
+        point: Vector2,
+    ) -> Option<Contact> {
+        let depth = overlap_vec.length();
+        if depth == 0f32 || depth.is_nan() {
+            return None;
+        }
+        Some(Contact {
+            entities,
+            bodies,
+            normal: overlap_vec / depth,
+            point,
+            depth,
+            normal_impulse: 0f32,
+            tangent_impulse: 0f32,
+            mass: 0f32,
+            normal_mass: 0f32,
+            tangent_mass: 0f32,
+            offsets: (Vector2::new(0f32, 0f32), Vector2::new(0f32, 0f32)),
+            velocity_bias: 0f32,
+            static_friction: 0f32,
+            dynamic_friction: 0f32,
+        })
+    }
+
+    /// Unit vector along the contact surface.
+    fn tangent(&self) -> Vector2 {
+        Vector2::new(-self.normal.y, self.normal.x)
+    }
+
+    /// Get the velocity of the second body relative to the first at the contact point.
+    fn get_relative_velocity(&self, bodies: &[Body]) -> Vector2 {
+        bodies[self.bodies.1].get_point_velocity(self.offsets.1)
+            - bodies[self.bodies.0].get_point_velocity(self.offsets.0)
+    }
+}
+
+/// Get one over the summed inverse masses, zero if neither can be moved.
+fn get_mass(inverse_mass: f32) -> f32 {
+    if inverse_mass > 0f32 {
+        1f32 / inverse_mass
+    } else {
+        0f32
+    }
+}
+
+/// Impulses of the contacts from the last step, used to warm start the solver.
+#[derive(Debug, Clone, Default)]
+pub struct ContactCache(pub HashMap<(u32, u32), (f32, f32)>);
+
+/// Apply an impulse at the contact point that pushes the first body away from the second.
+fn apply_impulse(bodies: &mut [Body], contact: &Contact, impulse: Vector2) {
+    let a = &mut bodies[contact.bodies.0];
+    a.velocity -= impulse * a.inverse_mass;
+    a.angular_velocity -= cross(contact.offsets.0, impulse) * a.inverse_inertia;
+    let b = &mut bodies[contact.bodies.1];
+    b.velocity += impulse * b.inverse_mass;
+    b.angular_velocity += cross(contact.offsets.1, impulse) * b.inverse_inertia;
+}
+
+/// Solve the contacts with sequential impulses at the contact points, which changes the linear and angular velocities.
+/// The overlap is then pushed apart without rotating the bodies.
+pub fn solve(
+    bodies: &mut [Body],
+    contacts: &mut [Contact],
+    cache: &mut ContactCache,
+    config: &PhysicsConfig,
+) {
+    for contact in contacts.iter_mut() {
+        let a = &bodies[contact.bodies.0];
+        let b = &bodies[contact.bodies.1];
+        contact.offsets = (contact.point - a.center, contact.point - b.center);
+        contact.mass = get_mass(a.inverse_mass + b.inverse_mass);
+        contact.normal_mass = get_mass(
+            a.get_inverse_mass_at(contact.offsets.0, contact.normal)
+                + b.get_inverse_mass_at(contact.offsets.1, contact.normal),
+        );
+        let tangent = contact.tangent();
+        contact.tangent_mass = get_mass(
+            a.get_inverse_mass_at(contact.offsets.0, tangent)
+                + b.get_inverse_mass_at(contact.offsets.1, tangent),
+        );
+
+        let normal_speed = contact.get_relative_velocity(bodies).dot(contact.normal);
+        contact.velocity_bias = if normal_speed < -config.restitution_threshold {
+            -normal_speed
+                * config
+                    .restitution_combine
+                    .combine(a.material.restitution, b.material.restitution)
+        } else {
+            0f32
+        };
+        contact.static_friction = config
+            .friction_combine
+            .combine(a.material.static_friction, b.material.static_friction);
+        contact.dynamic_friction = config
+            .friction_combine
+            .combine(a.material.dynamic_friction, b.material.dynamic_friction);
+
+        if config.warm_starting {
+            if let Some((normal_impulse, tangent_impulse)) = cache.0.get(&contact.entities) {
+                contact.normal_impulse = *normal_impulse;
+                contact.tangent_impulse = *tangent_impulse;
+                let impulse = contact.normal * contact.normal_impulse
+                    + contact.tangent() * contact.tangent_impulse;
+                apply_impulse(bodies, contact, impulse);
+            }
+        }
+    }
+
+    for _ in 0..config.velocity_iterations {
+        for contact in contacts.iter_mut() {
+            if contact.normal_mass == 0f32 {
+                continue;
+            }
+            let tangent = contact.tangent();
+
+            // friction is limited by how hard the bodies are pushed together
+            let relative_velocity = contact.get_relative_velocity(bodies);
+            let lambda = -relative_velocity.dot(tangent) * contact.tangent_mass;
+            let mut tangent_impulse = contact.tangent_impulse + lambda;
+            if tangent_impulse.abs() > contact.static_friction * contact.normal_impulse {
+                let max_friction = contact.dynamic_friction * contact.normal_impulse;
+                tangent_impulse = tangent_impulse.max(-max_friction).min(max_friction);
+            }
+            let change = tangent_impulse - contact.tangent_impulse;
+            contact.tangent_impulse = tangent_impulse;
+            apply_impulse(bodies, contact, tangent * change);
+
+            // the accumulated normal impulse can only push the bodies apart
+            let relative_velocity = contact.get_relative_velocity(bodies);
+            let lambda = (-relative_velocity.dot(contact.normal) + contact.velocity_bias)
+                * contact.normal_mass;
+            let normal_impulse = (contact.normal_impulse + lambda).max(0f32);
+            let change = normal_impulse - contact.normal_impulse;
+            contact.normal_impulse = normal_impulse;
+            apply_impulse(bodies, contact, contact.normal * change);
+        }
+    }
+
+    for _ in 0..config.position_iterations {
+        for contact in contacts.iter() {
+            if contact.mass == 0f32 {
+                continue;
+            }
+            let a = &bodies[contact.bodies.0];
+            let b = &bodies[contact.bodies.1];
+            let depth = contact.depth - (b.correction - a.correction).dot(contact.normal);
+            let push = (depth - config.penetration_slop).max(0f32)
+                * config.position_correction
+                * contact.mass;
+            if push == 0f32 {
+                continue;
+            }
+            let a = &mut bodies[contact.bodies.0];
+            a.correction -= contact.normal * (push * a.inverse_mass);
+            let b = &mut bodies[contact.bodies.1];
+            b.correction += contact.normal * (push * b.inverse_mass);
+        }
+    }
+
+    for body in bodies.iter_mut() {
+        body.position += body.correction;
+        body.correction = Vector2::new(0f32, 0f32);
+    }
+
+    cache.0.clear();
+    cache.0.extend(
+        contacts
+            .iter()
+            .map(|c| (c.entities, (c.normal_impulse, c.tangent_impulse))),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dynamic(position: Vector2, velocity: Vector2, mass: f32, restitution: f32) -> Body {
+        let mut physics = Physics::new(mass).with_material(Material::new(restitution, 0f32, 0f32));
+        physics.velocity = velocity;
+        Body::new(position, &physics)
+    }
+
+    fn solve_one(bodies: &mut [Body], overlap_vec: Vector2, point: Vector2) -> Contact {
+        let mut contacts: Vec<_> = Contact::new((0, 1), (0, 1), overlap_vec, point)
+            .into_iter()
+            .collect();
+        let mut cache = ContactCache::default();
+        solve(bodies, &mut contacts, &mut cache, &PhysicsConfig::default());
+        contacts.remove(0)
+    }
+
+    #[test]
+    fn resting_contact_does_not_bounce() {
+        let mut bodies = [
+            dynamic(
+                Vector2::new(0f32, 0f32),
+                Vector2::new(0f32, 5f32),
+                1f32,
+                1f32,
+            ),
+            Body::fixed(Vector2::new(0f32, 10f32), Material::new(1f32, 0f32, 0f32)),
+        ];
+        let contact = solve_one(
+            &mut bodies,
+            Vector2::new(0f32, 2f32),
+            Vector2::new(5f32, 10f32),
+        );
+        assert!(bodies[0].velocity.length() < 1e-4);
+        assert!(contact.normal_impulse > 0f32);
+        // only the overlap past the slop is pushed out, a bit on each iteration
+        assert!(bodies[0].position.y < 0f32 && bodies[0].position.y > -1.5f32);
+        assert_eq!(bodies[1].position, Vector2::new(0f32, 10f32));
+    }
+
+    #[test]
+    fn restitution_bounces_fast_hits() {
+        for &(restitution, expected) in &[(1f32, -100f32), (0.5f32, -50f32), (0f32, 0f32)] {
+            let mut bodies = [
+                dynamic(
+                    Vector2::new(0f32, 0f32),
+                    Vector2::new(100f32, 0f32),
+                    1f32,
+                    restitution,
+                ),
+                Body::fixed(
+                    Vector2::new(10f32, 0f32),
+                    Material::new(restitution, 0f32, 0f32),
+                ),
+            ];
+            solve_one(
+                &mut bodies,
+                Vector2::new(0.1f32, 0f32),
+                Vector2::new(10f32, 5f32),
+            );
+            assert!((bodies[0].velocity.x - expected).abs() < 1e-3);
+            assert!(bodies[0].velocity.y.abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn mass_ratio_splits_the_response() {
+        let mut bodies = [
+            dynamic(
+                Vector2::new(0f32, 0f32),
+                Vector2::new(8f32, 0f32),
+                1f32,
+                0f32,
+            ),
+            dynamic(
+                Vector2::new(10f32, 0f32),
+                Vector2::new(0f32, 0f32),
+                3f32,
+                0f32,
+            ),
+        ];
+        solve_one(
+            &mut bodies,
+            Vector2::new(4f32, 0f32),
+            Vector2::new(10f32, 5f32),
+        );
+        // momentum is kept and the bodies move on together
+        assert!((bodies[0].velocity.x - 2f32).abs() < 1e-3);
+        assert!((bodies[1].velocity.x - 2f32).abs() < 1e-3);
+        // the lighter body is pushed out three times as far
+        let moved = (-bodies[0].position.x, bodies[1].position.x - 10f32);
+        assert!(moved.0 > 0f32 && moved.1 > 0f32);
+        assert!((moved.0 - 3f32 * moved.1).abs() < 1e-3);
+    }
+
+    #[test]
+    fn off_center_hits_spin_the_body() {
+        let mut bodies = [
+            dynamic(
+                Vector2::new(0f32, 0f32),
+                Vector2::new(10f32, 0f32),
+                1f32,
+                0f32,
+            )
+            .with_rotation(Vector2::new(5f32, 5f32), 1f32 / 50f32),
+            Body::fixed(Vector2::new(10f32, 0f32), Material::new(0f32, 0f32, 0f32)),
+        ];
+        let contact = solve_one(
+            &mut bodies,
+            Vector2::new(0.1f32, 0f32),
+            Vector2::new(10f32, 0f32),
+        );
+        // the point above the center is stopped, so the body turns the other way
+        assert!(bodies[0].angular_velocity < 0f32);
+        assert!(bodies[0].velocity.x > 0f32 && bodies[0].velocity.x < 10f32);
+        let point_velocity = bodies[0].get_point_velocity(contact.offsets.0);
+        assert!(point_velocity.x.abs() < 1e-3);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::bvh::BVHTree;
+use crate::broad_phase::{BroadPhase, DynBroadPhase};
 use crate::collider;
 use crate::Entities;
 use crate::World;
@@ -10,6 +10,11 @@ use specs::{Component, VecStorage};
 #[storage(VecStorage)]
 pub struct Position(pub Vector2);
 
+/// The orientation of an entity in radians, entities without it are not rotated.
+#[derive(Debug, Clone, Component)]
+#[storage(VecStorage)]
+pub struct Rotation(pub f32);
+
 #[derive(Debug, Component)]
 #[storage(VecStorage)]
 pub struct Frozen;
@@ -29,17 +34,25 @@ pub fn from_tuple(t: [f32; 2]) -> Vector2 {
     Vector2::new(t[0], t[1])
 }
 
+/// Rotate a vector around the origin by an angle in radians.
+pub fn rotate(v: Vector2, angle: f32) -> Vector2 {
+    let (sin, cos) = angle.sin_cos();
+    Vector2::new(v.x * cos - v.y * sin, v.x * sin + v.y * cos)
+}
+
+/// Add an entity to the broad phase, if it has been built.
 pub fn register_ent(
     tuple_data: (&collider::Collider, Vector2, collider::AABB, u32),
     world: &mut World,
 ) {
-    let mut bvh_write: Write<Option<BVHTree>> = world.system_data();
-    if let Some(ref mut bvh) = *bvh_write {
-        bvh.insert(&tuple_data);
+    let mut broad_phase_write: Write<Option<DynBroadPhase>> = world.system_data();
+    if let Some(ref mut broad_phase) = *broad_phase_write {
+        broad_phase.insert(&tuple_data);
     }
 }
 
-pub fn delete_ent(ent: u32, ents: &mut Entities, bvh: &mut BVHTree) {
-    bvh.delete(ent);
+/// Delete an entity and remove it from the broad phase.
+pub fn delete_ent(ent: u32, ents: &mut Entities, broad_phase: &mut dyn BroadPhase) {
+    broad_phase.remove(ent);
     ents.delete(ents.entity(ent)).unwrap();
 }